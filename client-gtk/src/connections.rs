@@ -1,7 +1,7 @@
 use failure::Error;
 use messages::Messages;
 use rusqlite::Connection as SqlConnection;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
@@ -10,6 +10,8 @@ use std::thread::{self, JoinHandle};
 use synac::common::{self, Packet};
 use synac::{Listener, Session, State};
 
+pub const DEVICE_NAME: &str = "GTK+ client";
+
 #[derive(Debug, Fail)]
 pub enum ConnectionError {
     #[fail(display = "invalid packet")]
@@ -17,7 +19,15 @@ pub enum ConnectionError {
     #[fail(display = "invalid token: password authentication needed")]
     InvalidToken,
     #[fail(display = "invalid password")]
-    InvalidPassword
+    InvalidPassword,
+    #[fail(display = "name is already taken")]
+    NameTaken,
+    #[fail(display = "too many failed logins, try again in {} seconds", _0)]
+    RateLimited(u64),
+    #[fail(display = "two-factor authentication is not supported by this client yet")]
+    TotpUnsupported,
+    #[fail(display = "unknown user: no account with that name")]
+    UnknownUser
 }
 
 pub struct Synac {
@@ -27,7 +37,10 @@ pub struct Synac {
     pub state: State,
 
     pub channel: Option<usize>,
-    pub messages: Messages
+    /// Users that were asked for, but haven't arrived yet
+    pub fetching: HashSet<usize>,
+    pub messages: Messages,
+    pub presence: HashMap<usize, common::Presence>
 }
 impl Synac {
     pub fn new(addr: SocketAddr, session: Session) -> Self {
@@ -38,7 +51,9 @@ impl Synac {
             state: State::new(),
 
             channel: None,
-            messages: Messages::new()
+            fetching: HashSet::new(),
+            messages: Messages::new(),
+            presence: HashMap::new()
         }
     }
 }
@@ -106,23 +121,57 @@ impl Connections {
     }
     pub fn connect<F>(&self, addr: SocketAddr, hash: String, token: Option<String>, password: F)
         -> Result<Session, Error>
-        where F: FnOnce() -> Option<(String, Rc<SqlConnection>)>
+        where F: FnOnce() -> Option<(String, bool, Rc<SqlConnection>)>
     {
         let mut session = Session::new(addr, hash)?;
 
         if let Some(token) = token {
-            session.login_with_token(false, self.nick.read().unwrap().clone(), token)?;
+            session.send(&Packet::Login(common::Login {
+                bot: false,
+                device: Some(String::from(DEVICE_NAME)),
+                events: false,
+                name: self.nick.read().unwrap().clone(),
+                password: None,
+                resume: None,
+                sync: common::SyncMode::Lazy,
+                token: Some(token),
+                totp: None
+            }))?;
             match session.read()? {
                 Packet::LoginSuccess(_) => {
                     session.set_nonblocking(true)?;
                     return Ok(session);
                 },
                 Packet::Err(common::ERR_LOGIN_INVALID) => {},
+                Packet::RateLimited(time) => return Err(ConnectionError::RateLimited(time).into()),
                 _ => return Err(ConnectionError::InvalidPacket.into())
             }
         }
-        if let Some((password, db)) = password() {
-            session.login_with_password(false, self.nick.read().unwrap().clone(), password)?;
+        if let Some((password, register, db)) = password() {
+            let nick = self.nick.read().unwrap().clone();
+            if register {
+                session.send(&Packet::Register(common::Register {
+                    bot: false,
+                    device: Some(String::from(DEVICE_NAME)),
+                    events: false,
+                    invite: None,
+                    name: nick,
+                    password: password,
+                    sync: common::SyncMode::Lazy
+                }))?;
+            } else {
+                session.send(&Packet::Login(common::Login {
+                    bot: false,
+                    device: Some(String::from(DEVICE_NAME)),
+                    events: false,
+                    name: nick,
+                    password: Some(password),
+                    resume: None,
+                    sync: common::SyncMode::Lazy,
+                    token: None,
+                    totp: None
+                }))?;
+            }
             match session.read()? {
                 Packet::LoginSuccess(login) => {
                     db.execute("UPDATE servers SET token = ? WHERE ip = ?", &[&login.token, &addr.to_string()]).unwrap();
@@ -131,6 +180,14 @@ impl Connections {
                 },
                 Packet::Err(common::ERR_LOGIN_INVALID) =>
                      return Err(ConnectionError::InvalidPassword.into()),
+                Packet::RateLimited(time) =>
+                     return Err(ConnectionError::RateLimited(time).into()),
+                Packet::LoginChallenge(_) =>
+                     return Err(ConnectionError::TotpUnsupported.into()),
+                Packet::Err(common::ERR_NAME_TAKEN) =>
+                     return Err(ConnectionError::NameTaken.into()),
+                Packet::Err(common::ERR_UNKNOWN_USER) =>
+                     return Err(ConnectionError::UnknownUser.into()),
                 _ => return Err(ConnectionError::InvalidPacket.into())
             }
         }
@@ -162,11 +219,26 @@ impl Connections {
                 if let Ok(ref mut synac) = server.join() {
                     let read = synac.listener.try_read(synac.session.inner_stream())?;
                     if let Some(packet) = read {
+                        // This client doesn't resume yet, so where events are in the journal doesn't matter
+                        let packet = match packet {
+                            Packet::EventReceive(event) => *event.inner,
+                            packet => packet
+                        };
                         synac.state.update(&packet);
                         if let Packet::MessageReceive(ref event) = packet {
+                            let author = event.inner.author;
+                            if !synac.state.users.contains_key(&author) && synac.fetching.insert(author) {
+                                synac.session.send(&Packet::UserFetch(common::UserFetch {
+                                    ids: vec![author]
+                                }))?;
+                            }
                             synac.messages.add(event.inner.clone());
                         } else if let Packet::MessageDeleteReceive(ref msg) = packet {
                             synac.messages.remove(msg.id);
+                        } else if let Packet::PresenceReceive(ref event) = packet {
+                            synac.presence.insert(event.id, event.inner.clone());
+                        } else if let Packet::UserReceive(ref event) = packet {
+                            synac.fetching.remove(&event.inner.id);
                         }
                         callback(synac, packet);
                     }
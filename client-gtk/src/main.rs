@@ -1,12 +1,14 @@
 #[macro_use] extern crate failure;
 extern crate chrono;
 extern crate gtk;
+extern crate migrate;
 extern crate rusqlite;
 extern crate synac;
 extern crate xdg;
 
 mod connections;
 mod messages;
+mod migrations;
 
 use gtk::prelude::*;
 use gtk::{
@@ -14,6 +16,7 @@ use gtk::{
     Box as GtkBox,
     Button,
     ButtonsType,
+    CheckButton,
     Dialog,
     DialogFlags,
     Entry,
@@ -52,7 +55,7 @@ fn main() {
             Err(err) => { eprintln!("error placing config: {}", err); return; }
         }
     };
-    let db = match SqlConnection::open(&path) {
+    let mut db = match SqlConnection::open(&path) {
         Ok(ok) => ok,
         Err(err) => {
             eprintln!("Failed to open database");
@@ -60,19 +63,20 @@ fn main() {
             return;
         }
     };
+    match migrate::migrate(&mut db, migrations::MIGRATIONS) {
+        Ok(_) => {},
+        Err(migrate::MigrationError::TooNew(version)) => {
+            eprintln!("Your database is at version {}, which is newer than this client.", version);
+            eprintln!("Did you downgrade?");
+            return;
+        },
+        Err(migrate::MigrationError::SqlError(err)) => {
+            eprintln!("Failed to migrate database");
+            eprintln!("{}", err);
+            return;
+        }
+    }
     let db = Rc::new(db);
-    db.execute("CREATE TABLE IF NOT EXISTS data (
-                    key     TEXT NOT NULL UNIQUE,
-                    value   TEXT NOT NULL
-                )", &[])
-        .expect("Couldn't create SQLite table");
-    db.execute("CREATE TABLE IF NOT EXISTS servers (
-                    ip      TEXT NOT NULL PRIMARY KEY,
-                    name    TEXT NOT NULL,
-                    hash    BLOB NOT NULL,
-                    token   TEXT
-                )", &[])
-        .expect("Couldn't create SQLite table");
 
     let nick = {
         let mut stmt = db.prepare("SELECT value FROM data WHERE key = 'nick'").unwrap();
@@ -266,6 +270,8 @@ fn main() {
                 Packet::ChannelDeleteReceive(_) => channels = true,
                 Packet::MessageReceive(_)       => messages = true,
                 Packet::MessageDeleteReceive(_)       => messages = true,
+                Packet::PresenceReceive(_)      => messages = true,
+                Packet::UserReceive(_)          => messages = true,
                 _ => {}
             }
         }) {
@@ -351,12 +357,15 @@ fn render_servers(connections: &Arc<Connections>, db: &Rc<SqlConnection>, server
                     entry.set_input_purpose(InputPurpose::Password);
                     entry.set_visibility(false);
                     content.add(&entry);
+                    let register = CheckButton::new_with_label("Create a new account");
+                    content.add(&register);
 
                     dialog.show_all();
                     dialog.run();
                     let text = entry.get_text().unwrap_or_default();
+                    let register = register.get_active();
                     dialog.destroy();
-                    Some((text, Rc::clone(&db_clone)))
+                    Some((text, register, Rc::clone(&db_clone)))
                 });
                 match result {
                     Ok(session) => {
@@ -467,10 +476,24 @@ fn render_messages(connection: Option<(&Arc<Connections>, SocketAddr)>, messages
                         let msgbox = GtkBox::new(Orientation::Vertical, 2);
                         let authorbox = GtkBox::new(Orientation::Horizontal, 4);
 
-                        let author = Label::new(&*server.state.users[&msg.author].name);
+                        let author = Label::new(server.state.users.get(&msg.author)
+                            .map(|user| &*user.name)
+                            .unwrap_or("unknown"));
                         author.set_xalign(0.0);
                         authorbox.add(&author);
 
+                        let presence = server.presence.get(&msg.author).cloned().unwrap_or_default();
+                        let status = Label::new(match presence.status {
+                            common::Status::Away => "away",
+                            common::Status::Dnd => "do not disturb",
+                            common::Status::Offline => "offline",
+                            common::Status::Online => "online"
+                        });
+                        if let Some(ref custom) = presence.custom {
+                            status.set_tooltip_text(Some(&**custom));
+                        }
+                        authorbox.add(&status);
+
                         authorbox.add(&Separator::new(Orientation::Horizontal));
 
                         let mut time = String::with_capacity(32); // just a guess
@@ -0,0 +1,16 @@
+/// Every change ever made to the schema, in order.
+/// `PRAGMA user_version` is the number of them a database has had applied.
+/// Never edit one that's been released, add a new one instead.
+pub const MIGRATIONS: &[&str] = &[
+    // 1: Everything from before there were migrations
+    "CREATE TABLE IF NOT EXISTS data (
+        key     TEXT NOT NULL UNIQUE,
+        value   TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS servers (
+        ip      TEXT NOT NULL PRIMARY KEY,
+        name    TEXT NOT NULL,
+        hash    BLOB NOT NULL,
+        token   TEXT
+    );"
+];
@@ -3,11 +3,15 @@ use synac::common::Packet;
 
 use frontend;
 
+pub const DEVICE_NAME: &str = "Terminal client";
+
 pub fn connect(
     addr: SocketAddr,
     connector: &Connector,
+    invite: Option<String>,
+    resume: Option<common::Resume>,
     screen: &frontend::Screen
-) -> Option<Session> {
+) -> Option<(Session, bool)> {
     // See https://github.com/rust-lang/rust/issues/35853
     macro_rules! println {
         () => { screen.log(String::new()); };
@@ -63,8 +67,21 @@ pub fn connect(
     };
 
     let mut id = None;
+    let mut resumed = false;
+    let mut seq = 0;
     if let Some(token) = token {
-        if let Err(err) = inner.login_with_token(false, nick.to_string(), token.to_string()) {
+        let packet = Packet::Login(common::Login {
+            bot: false,
+            device: Some(String::from(DEVICE_NAME)),
+            events: true,
+            name: nick.to_string(),
+            password: None,
+            resume: resume.clone(),
+            sync: common::SyncMode::Lazy,
+            token: Some(token.to_string()),
+            totp: None
+        });
+        if let Err(err) = inner.send(&packet) {
             println!("Could not request login");
             println!("{}", err);
             return None;
@@ -73,16 +90,18 @@ pub fn connect(
         match inner.read() {
             Ok(Packet::LoginSuccess(login)) => {
                 id = Some(login.id);
-                if login.created {
-                    println!("Tried to log in with your token: Apparently an account was created.");
-                    println!("I think you should stay away from this server. It's weird.");
-                    return None;
-                }
+                resumed = login.resumed;
+                seq = login.seq;
                 println!("Logged in as user #{}", login.id);
             },
+            Ok(Packet::RateLimited(time)) => {
+                println!("Too many failed logins. You may try again in {} seconds.", time);
+                return None;
+            },
             Ok(Packet::Err(code)) => match code {
                 common::ERR_LOGIN_INVALID |
-                common::ERR_MISSING_FIELD => {},
+                common::ERR_MISSING_FIELD |
+                common::ERR_UNKNOWN_USER => {},
                 common::ERR_LIMIT_REACHED => {
                     println!("Username too long");
                     return None;
@@ -117,64 +136,137 @@ pub fn connect(
     }
 
     if id.is_none() {
-        println!("If you don't have an account, choose a new password here.");
-        println!("Otherwise, enter your existing one.");
         println!("Password: ");
         let pass = readpass!({ return None; });
 
-        if let Err(err) = inner.login_with_password(false, nick.to_string(), pass) {
-            println!("Could not request login");
-            println!("{}", err);
-            return None;
-        }
+        let mut invite = invite;
+        let mut packet = Packet::Login(common::Login {
+            bot: false,
+            device: Some(String::from(DEVICE_NAME)),
+            events: true,
+            name: nick.to_string(),
+            password: Some(pass.clone()),
+            resume: resume.clone(),
+            sync: common::SyncMode::Lazy,
+            token: None,
+            totp: None
+        });
+        loop {
+            if let Err(err) = inner.send(&packet) {
+                println!("Could not request login");
+                println!("{}", err);
+                return None;
+            }
 
-        match inner.read() {
-            Ok(Packet::LoginSuccess(login)) => {
-                db.execute(
-                    "UPDATE servers SET token = ? WHERE ip = ?",
-                    &[&login.token, &addr.to_string()]
-                ).unwrap();
-                if login.created {
-                    println!("Account created");
-                }
-                id = Some(login.id);
-                println!("Logged in as user #{}", login.id);
-            },
-            Ok(Packet::Err(code)) => match code {
-                common::ERR_LIMIT_REACHED => {
-                    println!("Username too long");
-                    return None;
+            match inner.read() {
+                Ok(Packet::LoginSuccess(login)) => {
+                    db.execute(
+                        "UPDATE servers SET token = ? WHERE ip = ?",
+                        &[&login.token, &addr.to_string()]
+                    ).unwrap();
+                    if let Packet::Register(_) = packet {
+                        println!("Account created");
+                    }
+                    id = Some(login.id);
+                    resumed = login.resumed;
+                    seq = login.seq;
+                    println!("Logged in as user #{}", login.id);
+                    break;
                 },
-                common::ERR_LOGIN_BANNED => {
-                    println!("You have been banned from this server. :(");
-                    return None;
+                Ok(Packet::LoginChallenge(_)) => {
+                    println!("This account uses two-factor authentication.");
+                    println!("Enter the code from your authenticator app, or a recovery code:");
+                    let code = readline!({ return None; });
+                    packet = Packet::Login(common::Login {
+                        bot: false,
+                        device: Some(String::from(DEVICE_NAME)),
+                        events: true,
+                        name: nick.to_string(),
+                        password: Some(pass.clone()),
+                        resume: resume.clone(),
+                        sync: common::SyncMode::Lazy,
+                        token: None,
+                        totp: Some(code)
+                    });
                 },
-                common::ERR_LOGIN_BOT => {
-                    println!("This account is a bot account");
+                Ok(Packet::RateLimited(time)) => {
+                    println!("Too many failed logins. You may try again in {} seconds.", time);
                     return None;
                 },
-                common::ERR_LOGIN_INVALID => {
-                    println!("Invalid credentials");
-                    return None;
+                Ok(Packet::Err(code)) => match code {
+                    common::ERR_INVITE_INVALID => {
+                        println!("This server requires a valid invite to register.");
+                        println!("Use /connect <ip[:port]> <invite> to supply one.");
+                        return None;
+                    },
+                    common::ERR_LIMIT_REACHED => {
+                        println!("Username too long");
+                        return None;
+                    },
+                    common::ERR_LOGIN_BANNED => {
+                        println!("You have been banned from this server. :(");
+                        return None;
+                    },
+                    common::ERR_LOGIN_BOT => {
+                        println!("This account is a bot account");
+                        return None;
+                    },
+                    common::ERR_LOGIN_INVALID => {
+                        println!("Invalid credentials");
+                        return None;
+                    },
+                    common::ERR_MISSING_FIELD => {
+                        println!("The password can't be empty");
+                        return None;
+                    },
+                    common::ERR_NAME_TAKEN => {
+                        println!("Somebody else just took that name");
+                        return None;
+                    },
+                    common::ERR_REGISTRATION_CLOSED => {
+                        println!("This server does not accept new accounts");
+                        return None;
+                    },
+                    common::ERR_TOTP_INVALID => {
+                        println!("Invalid two-factor code");
+                        return None;
+                    },
+                    common::ERR_UNKNOWN_USER => {
+                        println!("There is no account called {} on this server.", nick);
+                        println!("Do you want to create it? [y/N]");
+                        let answer = readline!({ return None; });
+                        if !answer.trim().eq_ignore_ascii_case("y") {
+                            return None;
+                        }
+                        packet = Packet::Register(common::Register {
+                            bot: false,
+                            device: Some(String::from(DEVICE_NAME)),
+                            events: true,
+                            invite: invite.take(),
+                            name: nick.to_string(),
+                            password: pass.clone(),
+                            sync: common::SyncMode::Lazy
+                        });
+                    },
+                    _ => {
+                        println!("The server responded with an invalid error. :/");
+                        return None;
+                    }
                 },
-                _ => {
-                    println!("The server responded with an invalid error. :/");
+                Ok(_) => {
+                    println!("The server responded with an invalid packet. :/");
+                    return None;
+                }
+                Err(err) => {
+                    println!("Failed to read from server");
+                    println!("{}", err);
                     return None;
                 }
-            },
-            Ok(_) => {
-                println!("The server responded with an invalid packet. :/");
-                return None;
-            }
-            Err(err) => {
-                println!("Failed to read from server");
-                println!("{}", err);
-                return None;
             }
         }
     }
     inner.inner_stream().get_ref().set_nonblocking(true).expect("Failed to make stream non-blocking");
-    Some(Session::new(addr, id.unwrap(), inner))
+    Some((Session::new(addr, id.unwrap(), inner, seq), resumed))
 }
 
 pub fn reconnect(
@@ -185,8 +277,18 @@ pub fn reconnect(
 ) {
     if err.kind() == std::io::ErrorKind::BrokenPipe {
         screen.log(String::from("Attempting reconnect..."));
-        if let Some(new) = connect(session.addr, &connector, screen) {
-            *session = new;
+        let resume = common::Resume {
+            last_seq: session.seq
+        };
+        if let Some((new, resumed)) = connect(session.addr, &connector, None, Some(resume), screen) {
+            if resumed {
+                // Only what was missed is sent, so everything from before still holds
+                session.fetching.clear();
+                session.inner = new.inner;
+                session.seq = new.seq;
+            } else {
+                *session = new;
+            }
         }
     }
 }
@@ -41,6 +41,28 @@ impl RustyCompleter for Completer {
         let mut output = Vec::new();
 
         if let Some(ref session) = *self.session.lock().unwrap() {
+            if line.starts_with('!') {
+                let mut previous = line[..start].split_whitespace();
+                match (previous.next(), previous.next()) {
+                    // Completing the bot
+                    (None, _) => output.extend(session.state.users.values()
+                        .filter(|user| user.bot)
+                        .map(|user| format!("!{}", user.name))
+                        .filter(|name| name.starts_with(word))),
+                    // Completing the command
+                    (Some(bot), None) => if let Some(bot) = find_user(&session.state.users, &bot[1..]) {
+                        if let Some(commands) = session.commands.get(&bot.id) {
+                            output.extend(commands.iter()
+                                .map(|command| command.name.clone())
+                                .filter(|name| name.starts_with(word)));
+                        }
+                    },
+                    _ => {}
+                }
+                if !output.is_empty() {
+                    return Ok((start, output));
+                }
+            }
             output.extend(session.state.users.values()
                 .map(|user| user.name.clone())
                 .filter(|name| name.starts_with(word)));
@@ -1,16 +1,29 @@
 pub fn help(query: &[&str], screen: &::frontend::Screen) {
     let all = query.is_empty();
 
+    if all || query.contains(&"backup") {
+        screen.log("\
+            backup\n\
+            Makes the current server save a snapshot of its database. Only the owner may do this.\
+        ".to_string());
+    }
     if all || query.contains(&"ban") || query.contains(&"unban") {
         screen.log("\
             ban/unban <user>\n\
             A ban prevents logging in as <user> and prevents creation of accounts on their IP.\
         ".to_string());
     }
+    if all || query.contains(&"bot") {
+        screen.log("\
+            bot <\"create\"/\"delete\"/\"reset\"> <name>\n\
+            Creates a bot called <name>, deletes it, or replaces its token.\n\
+            The new token is printed, and is the only way for the bot to log in.\
+        ".to_string());
+    }
     if all || query.contains(&"connect") {
         let mut text = String::from("\
-            connect <ip[:port]>\n\
-            Connects to IP. Default port is \
+            connect <ip[:port]> [invite]\n\
+            Connects to IP, optionally registering with [invite]. Default port is \
         ");
         text.push_str(&::common::DEFAULT_PORT.to_string());
         text.push('.');
@@ -50,6 +63,15 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
             If left empty, it shows all of them.\
         ".to_string());
     }
+    if all || query.contains(&"incoming") {
+        screen.log("\
+            incoming create <bot> <channel>\n\
+            incoming delete <id>\n\
+            incoming list <channel>\n\
+            Lets anything that has the printed token post to <channel> as <bot>,\n\
+            by sending {\"text\": \"...\"} to POST /messages with an \"Authorization: Bearer <token>\" header.\
+        ".to_string());
+    }
     if all || query.contains(&"info") {
         screen.log("\
             info <channel/group/user>\n\
@@ -57,6 +79,13 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
             Useful for getting the ID for functions that require such.\
         ".to_string());
     }
+    if all || query.contains(&"invite") {
+        screen.log("\
+            invite create [max uses] [hours]\n\
+            invite revoke <code>\n\
+            Creates an invite code for registering, or revokes an existing one.\
+        ".to_string());
+    }
     if all || query.contains(&"join") {
         screen.log("\
             join <channel>\n\
@@ -65,8 +94,9 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
     }
     if all || query.contains(&"list") {
         screen.log("\
-            list <\"channels\"/\"groups\"/\"users\">\n\
-            Lists all <\"channels\"/\"groups\"/\"users\">.\
+            list <\"channels\"/\"commands\"/\"groups\"/\"users\">\n\
+            Lists all <\"channels\"/\"commands\"/\"groups\"/\"users\">.\n\
+            Commands are the ones bots have registered, used like !bot command.\
         ".to_string());
     }
     if all || query.contains(&"msg") {
@@ -102,10 +132,42 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
             Prepares for encrypted messaging with /msg.\
         ".to_string());
     }
+    if all || query.contains(&"status") {
+        screen.log("\
+            status <\"online\"/\"away\"/\"dnd\"> [custom status]\n\
+            Sets your status on the current server, shown to others in /list users.\n\
+            Everyone appears offline when they aren't connected.\
+        ".to_string());
+    }
+    if all || query.contains(&"tokens") {
+        screen.log("\
+            tokens [revoke <id>]\n\
+            Lists the devices logged in to your account on the current server.\n\
+            With revoke, logs out the device with <id>.\
+        ".to_string());
+    }
+    if all || query.contains(&"totp") {
+        screen.log("\
+            totp <\"enroll\"/\"confirm\"/\"disable\"> [code]\n\
+            Sets up two-factor authentication on the current server.\n\
+            Run enroll, add the secret to your authenticator app and confirm with a code.\n\
+            To disable it again, supply a code or one of your recovery codes.\
+        ".to_string());
+    }
     if all || query.contains(&"update") {
         screen.log("\
             update <\"channel\"/\"group\"> <id>\n\
             Interactively edits <\"channel\"/\"group\"> with <id>.\
         ".to_string());
     }
+    if all || query.contains(&"webhook") {
+        screen.log("\
+            webhook create <channel> <url>\n\
+            webhook delete <id>\n\
+            webhook list <channel>\n\
+            Makes the server POST events in <channel> to <url> as JSON.\n\
+            Only the server owner can create webhooks, and only to public addresses.\n\
+            Each request is signed in the X-Synac-Signature header with the printed secret.\
+        ".to_string());
+    }
 }
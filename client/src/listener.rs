@@ -59,10 +59,72 @@ pub fn listen(
                 continue;
             }
             if let Some(packet) = packet.unwrap() {
+                let packet = match packet {
+                    Packet::EventReceive(event) => {
+                        session.seq = event.seq;
+                        *event.inner
+                    },
+                    packet => packet
+                };
                 screen.delete(LogEntryId::Sending);
                 session.state.update(&packet);
 
                 match packet {
+                    Packet::BackupReceive(event) => {
+                        println!("Backed up the server to {} in its backup directory", event.name);
+                    },
+                    Packet::BotTokenReceive(event) => {
+                        let name = session.state.users.get(&event.id)
+                            .map(|user| &*user.name)
+                            .unwrap_or("your new bot");
+                        println!("Token for {}: {}", name, event.token);
+                        println!("Keep it secret, anyone with it can log in as the bot.");
+                    },
+                    Packet::CommandListReceive(event) => {
+                        if event.commands.is_empty() {
+                            session.commands.remove(&event.bot);
+                        } else {
+                            session.commands.insert(event.bot, event.commands);
+                        }
+                    },
+                    Packet::CommandResponseReceive(event) => {
+                        session.fetch_user(event.author);
+                        let bot = session.state.users.get(&event.author)
+                            .map(|user| &*user.name)
+                            .unwrap_or("unknown");
+                        println!("{}: {}", bot, frontend::sanitize(event.text));
+                    },
+                    Packet::CommandTimeoutReceive(_) => {
+                        println!("The bot didn't respond. Is it offline?");
+                    },
+                    Packet::IncomingWebhookReceive(event) => {
+                        let webhook = event.inner;
+                        let bot = session.state.users.get(&webhook.bot)
+                            .map(|user| &*user.name)
+                            .unwrap_or("unknown");
+                        match webhook.token {
+                            Some(token) => {
+                                println!("Incoming webhook #{} for {}: {}", webhook.id, bot, token);
+                                println!("Keep it secret, anyone with it can post as the bot.");
+                            },
+                            None => println!("Incoming webhook #{} for {}", webhook.id, bot)
+                        }
+                    },
+                    Packet::InviteReceive(event) => {
+                        println!("Created invite: {}", event.inner.code);
+                    },
+                    Packet::LimitsReceive(event) => {
+                        // The first one is just part of logging in
+                        if session.limits.is_some() && session.limits.as_ref() != Some(&event.inner) {
+                            println!(
+                                "The server changed its limits: messages are now {}-{} bytes, {} cheap requests per 10 seconds.",
+                                event.inner.message_min,
+                                event.inner.message_max,
+                                event.inner.requests_cheap_per_10_seconds
+                            );
+                        }
+                        session.limits = Some(event.inner);
+                    },
                     Packet::LoginSuccess(event) => {
                         db.lock().unwrap().execute(
                             "UPDATE servers SET token = ? WHERE ip = ?",
@@ -76,28 +138,31 @@ pub fn listen(
                     Packet::MessageReceive(msg) => {
                         let msg = msg.inner;
                         session.typing.remove(&(msg.author, msg.channel));
+                        session.fetch_user(msg.author);
 
-                        if let Some(user) = session.state.users.get(&msg.author) {
-                            if session.channel == Some(msg.channel) {
-                                screen.log_with_id(
-                                    format!(
-                                        "{} (ID #{}): {}",
-                                        user.name,
-                                        msg.id,
-                                        frontend::sanitize(
-                                            String::from_utf8_lossy(&msg.text)
-                                                .into_owned()
-                                        )
-                                    ),
-                                    LogEntryId::Message(msg.id)
-                                );
-                            }
-                            if msg.author == session.id {
-                                session.last = Some((msg.id, msg.text));
-                            }
+                        if session.channel == Some(msg.channel) {
+                            let name = session.state.users.get(&msg.author)
+                                .map(|user| &*user.name)
+                                .unwrap_or("unknown");
+                            screen.log_with_id(
+                                format!(
+                                    "{} (ID #{}): {}",
+                                    name,
+                                    msg.id,
+                                    frontend::sanitize(
+                                        String::from_utf8_lossy(&msg.text)
+                                            .into_owned()
+                                    )
+                                ),
+                                LogEntryId::Message(msg.id)
+                            );
+                        }
+                        if msg.author == session.id {
+                            session.last = Some((msg.id, msg.text));
                         }
                     },
                     Packet::PMReceive(msg) => {
+                        session.fetch_user(msg.author);
                         let db = db.lock().unwrap();
                         let mut stmt = db.prepare_cached("SELECT private FROM pms WHERE recipient = ?")
                             .unwrap();
@@ -128,20 +193,79 @@ pub fn listen(
                             }
                         }
                     }
+                    Packet::PresenceReceive(event) => {
+                        if event.inner.status == common::Status::Offline {
+                            session.presence.remove(&event.id);
+                        } else {
+                            session.fetch_user(event.id);
+                            session.presence.insert(event.id, event.inner);
+                        }
+                    },
                     Packet::RateLimited(time) => {
                         println!("Slow down! You may try again in {} seconds.", time);
                     },
+                    Packet::ServerShutdown(event) => {
+                        println!("The server is shutting down: {}", event.reason);
+                        if let Some(seconds) = event.reconnect_after {
+                            println!("It should be back in about {} seconds.", seconds);
+                        }
+                    },
+                    Packet::TokenReceive(event) => {
+                        let token = event.inner;
+                        println!(
+                            "Token #{}: {}{} (last used from {})",
+                            token.id,
+                            token.name,
+                            if token.current { " [current]" } else { "" },
+                            token.last_ip
+                        );
+                    },
+                    Packet::TotpRecoveryReceive(event) => {
+                        println!("Two-factor authentication is now enabled.");
+                        println!("If you lose your authenticator, you can log in with one of these recovery codes.");
+                        println!("Each of them only works once. Store them somewhere safe:");
+                        for code in event.codes {
+                            println!(code);
+                        }
+                    },
+                    Packet::TotpSecretReceive(event) => {
+                        println!("Add this secret to your authenticator app:");
+                        println!(event.secret);
+                        println!("Then run /totp confirm <code> with the code it shows.");
+                    },
                     Packet::TypingReceive(event) => {
+                        session.fetch_user(event.author);
                         if event.author != session.id {
                             session.typing.insert((event.author, event.channel), Instant::now());
                         }
                     },
+                    Packet::UserDeleteReceive(event) => {
+                        session.state.users.remove(&event.inner.id);
+                        session.commands.remove(&event.inner.id);
+                        session.presence.remove(&event.inner.id);
+                    },
+                    Packet::UserReceive(event) => {
+                        session.fetching.remove(&event.inner.id);
+                    },
+                    Packet::WebhookReceive(event) => {
+                        let webhook = event.inner;
+                        println!("Webhook #{}: {} (secret: {})", webhook.id, webhook.url, webhook.secret);
+                    },
+                    Packet::Err(common::ERR_BACKUP_FAILED) => {
+                        println!("The server failed to make a backup");
+                    },
+                    Packet::Err(common::ERR_COMMAND_INVALID) => {
+                        println!("Invalid arguments for that command");
+                    },
                     Packet::Err(common::ERR_GROUP_INVALID_POS) => {
                         println!("Invalid group position");
                     },
                     Packet::Err(common::ERR_GROUP_LOCKED_NAME) => {
                         println!("Can not change the name of that group");
                     },
+                    Packet::Err(common::ERR_INVITE_INVALID) => {
+                        println!("No such invite");
+                    },
                     Packet::Err(common::ERR_LIMIT_REACHED) => {
                         println!("Too short or too long. No idea which");
                     },
@@ -154,12 +278,36 @@ pub fn listen(
                     Packet::Err(common::ERR_NAME_TAKEN) => {
                         println!("Name is already taken")
                     },
+                    Packet::Err(common::ERR_STATUS_INVALID) => {
+                        println!("You can't pick that status");
+                    },
+                    Packet::Err(common::ERR_TOTP_ENABLED) => {
+                        println!("Two-factor authentication is already enabled");
+                    },
+                    Packet::Err(common::ERR_TOTP_INVALID) => {
+                        println!("Invalid two-factor code");
+                    },
+                    Packet::Err(common::ERR_UNKNOWN_BOT) => {
+                        println!("No such bot");
+                    },
                     Packet::Err(common::ERR_UNKNOWN_CHANNEL) => {
                         println!("This channel was deleted");
                     },
+                    Packet::Err(common::ERR_UNKNOWN_COMMAND) => {
+                        println!("That bot has no such command");
+                    },
                     Packet::Err(common::ERR_UNKNOWN_GROUP) => {
                         println!("This group was deleted");
                     },
+                    Packet::Err(common::ERR_UNKNOWN_TOKEN) => {
+                        println!("No such token");
+                    },
+                    Packet::Err(common::ERR_UNKNOWN_WEBHOOK) => {
+                        println!("No such webhook");
+                    },
+                    Packet::Err(common::ERR_WEBHOOK_INVALID) => {
+                        println!("Webhooks need an http:// or https:// URL");
+                    },
                     Packet::Err(err) => {
                         println!("Unimplemented error: {:?}", err);
                     },
@@ -1,3 +1,4 @@
+extern crate migrate;
 extern crate openssl;
 extern crate rusqlite;
 extern crate rustyline;
@@ -6,7 +7,7 @@ extern crate synac;
 use synac::State;
 use synac::common::{self, Packet};
 use rusqlite::Connection as SqlConnection;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::net::SocketAddr;
@@ -14,12 +15,13 @@ use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 mod connect;
 mod frontend;
 mod help;
 mod listener;
+mod migrations;
 mod parser;
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -39,23 +41,46 @@ pub struct Session {
 
     addr: SocketAddr,
     channel: Option<usize>,
+    command_next: usize,
+    commands: HashMap<usize, Vec<common::CommandInfo>>,
+    /// Users that were asked for, but haven't arrived yet
+    fetching: HashSet<usize>,
     id: usize,
     last: Option<(usize, Vec<u8>)>,
+    limits: Option<common::Limits>,
+    presence: HashMap<usize, common::Presence>,
+    /// The last event received, to resume from after a reconnect
+    seq: u64,
     typing: HashMap<(usize, usize), Instant>
 }
 impl Session {
-    pub fn new(addr: SocketAddr, id: usize, inner: synac::Session) -> Session {
+    pub fn new(addr: SocketAddr, id: usize, inner: synac::Session, seq: u64) -> Session {
         Session {
             inner: inner,
             state: State::new(),
 
             addr: addr,
             channel: None,
+            command_next: 0,
+            commands: HashMap::new(),
+            fetching: HashSet::new(),
             id: id,
             last: None,
+            limits: None,
+            presence: HashMap::new(),
+            seq: seq,
             typing: HashMap::new(),
         }
     }
+    /// Asks the server about a user this session wasn't told about at login.
+    pub fn fetch_user(&mut self, id: usize) {
+        if self.state.users.contains_key(&id) || !self.fetching.insert(id) {
+            return;
+        }
+        let _ = self.inner.send(&Packet::UserFetch(common::UserFetch {
+            ids: vec![id]
+        }));
+    }
 }
 
 fn main() {
@@ -105,7 +130,7 @@ fn main() {
         return;
     }
     path.push("data.sqlite");
-    let db = match SqlConnection::open(&path) {
+    let mut db = match SqlConnection::open(&path) {
         Ok(ok) => ok,
         Err(err) => {
             eprintln!("Failed to open database");
@@ -113,23 +138,19 @@ fn main() {
             return;
         }
     };
-    db.execute("CREATE TABLE IF NOT EXISTS data (
-                    key     TEXT NOT NULL UNIQUE,
-                    value   TEXT NOT NULL
-                )", &[])
-        .expect("Couldn't create SQLite table");
-    db.execute("CREATE TABLE IF NOT EXISTS pms (
-                    private     BLOB NOT NULL,
-                    public      BLOB NOT NULL,
-                    recipient   INTEGER NOT NULL PRIMARY KEY
-                )", &[])
-        .expect("Couldn't create SQLite table");
-    db.execute("CREATE TABLE IF NOT EXISTS servers (
-                    ip      TEXT NOT NULL PRIMARY KEY,
-                    key     BLOB NOT NULL,
-                    token   TEXT
-                )", &[])
-        .expect("Couldn't create SQLite table");
+    match migrate::migrate(&mut db, migrations::MIGRATIONS) {
+        Ok(_) => {},
+        Err(migrate::MigrationError::TooNew(version)) => {
+            eprintln!("Your database is at version {}, which is newer than this client.", version);
+            eprintln!("Did you downgrade?");
+            return;
+        },
+        Err(migrate::MigrationError::SqlError(err)) => {
+            eprintln!("Failed to migrate database");
+            eprintln!("{}", err);
+            return;
+        }
+    }
 
     let nick = {
         let mut stmt = db.prepare("SELECT value FROM data WHERE key = 'nick'").unwrap();
@@ -232,6 +253,13 @@ fn main() {
             }
 
             match &*command {
+                "backup" => {
+                    usage!(0, "backup");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    let packet = Packet::BackupCreate(common::BackupCreate);
+                    write!(session, packet, {})
+                },
                 "ban" | "unban" => {
                     usage!(1, "ban/unban <user>");
                     let mut session = session.lock().unwrap();
@@ -251,8 +279,36 @@ fn main() {
                     });
                     write!(session, packet, {})
                 },
+                "bot" => {
+                    usage!(2, "bot <\"create\"/\"delete\"/\"reset\"> <name>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    if args[0] == "create" {
+                        let packet = Packet::BotCreate(common::BotCreate {
+                            name: args.remove(1)
+                        });
+                        write!(session, packet, {});
+                        continue;
+                    }
+                    let id = match find_user(&session.state.users, &args[1]) {
+                        Some(user) if user.bot => user.id,
+                        Some(_) => { println!("That's not a bot!"); continue; },
+                        None => { println!("No such user"); continue; }
+                    };
+                    let packet = match &*args[0] {
+                        "delete" => Packet::BotDelete(common::BotDelete {
+                            id: id
+                        }),
+                        "reset" => Packet::BotTokenReset(common::BotTokenReset {
+                            id: id
+                        }),
+                        _ => { println!("Unable to do that with a bot"); continue; }
+                    };
+                    write!(session, packet, {})
+                },
                 "connect" => {
-                    usage!(1, "connect <ip[:port]>");
+                    usage_min!(1, "connect <ip[:port]> [invite]");
+                    usage_max!(2, "connect <ip[:port]> [invite]");
                     let mut session = session.lock().unwrap();
                     if session.is_some() {
                         println!("You have to disconnect before doing that.");
@@ -266,7 +322,9 @@ fn main() {
                             continue;
                         }
                     };
-                    *session = connect::connect(addr, &connector, &screen);
+                    let invite = if args.len() == 2 { Some(args.remove(1)) } else { None };
+                    *session = connect::connect(addr, &connector, invite, None, &screen)
+                        .map(|(session, _)| session);
                 },
                 "create" => {
                     usage_min!(2, "create <\"channel\"/\"group\"> <name> [data]");
@@ -281,7 +339,8 @@ fn main() {
                             }
                             Packet::ChannelCreate(common::ChannelCreate {
                                 overrides: HashMap::new(),
-                                name: name
+                                name: name,
+                                retention_days: None
                             })
                         },
                         "group" => {
@@ -367,6 +426,56 @@ fn main() {
                     let borrowed: Vec<_> = args.iter().map(|arg| &**arg).collect();
                     help::help(&*borrowed, &screen);
                 },
+                "incoming" => {
+                    usage_min!(2, "incoming <\"create\"/\"delete\"/\"list\"> <...>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    macro_rules! find_channel {
+                        ($name:expr) => {
+                            match session.state.channels.values().find(|channel| channel.name == $name.trim_left_matches('#')) {
+                                Some(channel) => channel.id,
+                                None => {
+                                    println!("No channel found with that name");
+                                    continue;
+                                }
+                            }
+                        }
+                    }
+                    let packet = match &*args[0] {
+                        "create" => {
+                            usage!(3, "incoming create <bot> <channel>");
+                            let bot = match find_user(&session.state.users, &args[1]) {
+                                Some(user) if user.bot => user.id,
+                                Some(_) => { println!("That's not a bot!"); continue; },
+                                None => { println!("No such user"); continue; }
+                            };
+                            Packet::IncomingWebhookCreate(common::IncomingWebhookCreate {
+                                bot: bot,
+                                channel: find_channel!(args[2])
+                            })
+                        },
+                        "delete" => {
+                            usage!(2, "incoming delete <id>");
+                            match args[1].parse() {
+                                Ok(id) => Packet::IncomingWebhookDelete(common::IncomingWebhookDelete {
+                                    id: id
+                                }),
+                                Err(_) => {
+                                    println!("Not a valid number");
+                                    continue;
+                                }
+                            }
+                        },
+                        "list" => {
+                            usage!(2, "incoming list <channel>");
+                            Packet::IncomingWebhookList(common::IncomingWebhookList {
+                                channel: find_channel!(args[1])
+                            })
+                        },
+                        _ => { println!("Unable to do that with an incoming webhook"); continue; }
+                    };
+                    write!(session, packet, {})
+                },
                 "info" => {
                     usage!(1, "info <channel/group/user>");
                     let mut session = session.lock().unwrap();
@@ -416,10 +525,55 @@ fn main() {
                                 println!("Banned.");
                             }
                             println!("Bot: {}", if user.bot { "true" } else { "false" });
+                            if let Some(owner) = user.owner.and_then(|owner| session.state.users.get(&owner)) {
+                                println!("Owner: {}", owner.name);
+                            }
                             println!("ID: #{}", user.id);
                         }
                     }
                 },
+                "invite" => {
+                    usage_min!(1, "invite <\"create\"/\"revoke\"> [...]");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    let packet = match &*args[0] {
+                        "create" => {
+                            usage_max!(3, "invite create [max uses] [hours]");
+                            let max_uses = match args.get(1).map(|uses| uses.parse()) {
+                                Some(Ok(0)) | Some(Err(_)) => {
+                                    println!("Not a valid number");
+                                    continue;
+                                },
+                                Some(Ok(uses)) => Some(uses),
+                                None => None
+                            };
+                            let expires = match args.get(2).map(|hours| hours.parse::<i64>()) {
+                                Some(Ok(hours)) => {
+                                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                                    Some(now.as_secs() as i64 + hours * 60 * 60)
+                                },
+                                Some(Err(_)) => {
+                                    println!("Not a valid number");
+                                    continue;
+                                },
+                                None => None
+                            };
+                            Packet::InviteCreate(common::InviteCreate {
+                                expires: expires,
+                                groups: Vec::new(),
+                                max_uses: max_uses
+                            })
+                        },
+                        "revoke" => {
+                            usage!(2, "invite revoke <code>");
+                            Packet::InviteRevoke(common::InviteRevoke {
+                                code: args.remove(1)
+                            })
+                        },
+                        _ => { println!("Unable to do that with an invite"); continue; }
+                    };
+                    write!(session, packet, {})
+                },
                 "join" => {
                     usage!(1, "join <channel>");
                     let mut session = session.lock().unwrap();
@@ -451,7 +605,7 @@ fn main() {
                     }
                 },
                 "list" => {
-                    usage!(1, "list <\"channels\"/\"groups\"/\"users\">");
+                    usage!(1, "list <\"channels\"/\"commands\"/\"groups\"/\"users\">");
                     let mut session = session.lock().unwrap();
                     let session = require_session!(session);
                     match &*args[0] {
@@ -469,6 +623,26 @@ fn main() {
                             });
                             println!(result);
                         },
+                        "commands" => {
+                            for (bot, commands) in &session.commands {
+                                let bot = match session.state.users.get(bot) {
+                                    Some(bot) => &bot.name,
+                                    None => continue
+                                };
+                                for command in commands {
+                                    let usage = command.args.iter().fold(String::new(), |mut acc, arg| {
+                                        acc.push_str(if arg.optional { " [" } else { " <" });
+                                        acc.push_str(&arg.name);
+                                        if arg.kind == common::ArgKind::Rest {
+                                            acc.push_str("...");
+                                        }
+                                        acc.push(if arg.optional { ']' } else { '>' });
+                                        acc
+                                    });
+                                    println!("!{} {}{}: {}", bot, command.name, usage, command.description);
+                                }
+                            }
+                        },
                         "groups" => {
                             // Read the above comment, thank you ---------------------------^
                             let mut groups: Vec<_> = session.state.groups.values().collect();
@@ -494,6 +668,9 @@ fn main() {
                                     if user.ban == *banned {
                                         if !acc.is_empty() { acc.push_str(", "); }
                                         acc.push_str(&user.name);
+                                        if let Some(presence) = session.presence.get(&user.id) {
+                                            acc.push_str(&presence_string(presence));
+                                        }
                                     }
                                     acc
                                 }));
@@ -654,6 +831,78 @@ fn main() {
                         &[&private, &key, &(id as i64)]
                     ).unwrap();
                 },
+                "status" => {
+                    usage_min!(1, "status <\"online\"/\"away\"/\"dnd\"> [custom status]");
+                    let status = match &*args[0] {
+                        "online" => common::Status::Online,
+                        "away" => common::Status::Away,
+                        "dnd" => common::Status::Dnd,
+                        _ => { println!("Unknown status"); continue; }
+                    };
+                    let custom = args[1..].join(" ");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    let packet = Packet::PresenceUpdate(common::PresenceUpdate {
+                        inner: common::Presence {
+                            custom: if custom.is_empty() { None } else { Some(custom) },
+                            status: status
+                        }
+                    });
+                    write!(session, packet, {})
+                },
+                "tokens" => {
+                    usage_max!(2, "tokens [revoke <id>]");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    let packet = if args.is_empty() {
+                        Packet::TokenList(common::TokenList)
+                    } else {
+                        usage!(2, "tokens [revoke <id>]");
+                        if args[0] != "revoke" {
+                            println!("Usage: /tokens [revoke <id>]");
+                            continue;
+                        }
+                        match args[1].parse() {
+                            Ok(id) => Packet::TokenRevoke(common::TokenRevoke {
+                                id: id
+                            }),
+                            Err(_) => {
+                                println!("Not a valid number");
+                                continue;
+                            }
+                        }
+                    };
+                    write!(session, packet, {})
+                },
+                "totp" => {
+                    usage_min!(1, "totp <\"enroll\"/\"confirm\"/\"disable\"> [code]");
+                    let packet = match &*args[0] {
+                        "enroll" => {
+                            usage!(1, "totp enroll");
+                            println!("Password: ");
+                            let password = readpass!({ continue; });
+                            Packet::TotpEnroll(common::TotpEnroll {
+                                password: password
+                            })
+                        },
+                        "confirm" => {
+                            usage!(2, "totp confirm <code>");
+                            Packet::TotpConfirm(common::TotpConfirm {
+                                code: args.remove(1)
+                            })
+                        },
+                        "disable" => {
+                            usage!(2, "totp disable <code>");
+                            Packet::TotpDisable(common::TotpDisable {
+                                code: args.remove(1)
+                            })
+                        },
+                        _ => { println!("Unknown two-factor action"); continue; }
+                    };
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    write!(session, packet, {})
+                },
                 "update" => {
                     usage!(2, "update <\"channel\"/\"group\"> <id>");
 
@@ -723,6 +972,24 @@ fn main() {
                             let mut name = name.trim();
                             if name.is_empty() { name = &channel.name }
 
+                            let current = match channel.retention_days {
+                                Some(days) => days.to_string(),
+                                None => String::from("default")
+                            };
+                            println!("Days to keep messages, 0 for forever or \"default\" [{}]: ", current);
+                            let retention = readline!({ continue; });
+                            let retention = match retention.trim() {
+                                "" => channel.retention_days,
+                                "default" => None,
+                                days => match days.parse() {
+                                    Ok(ok) => Some(ok),
+                                    Err(_) => {
+                                        println!("Not a valid number");
+                                        continue;
+                                    }
+                                }
+                            };
+
                             let overrides = match screen.get_channel_overrides(channel.overrides.clone(), session) {
                                 Ok(ok) => ok,
                                 Err(_) => continue
@@ -732,7 +999,8 @@ fn main() {
                                 inner: common::Channel {
                                     id: channel.id,
                                     name: name.to_string(),
-                                    overrides: overrides
+                                    overrides: overrides,
+                                    retention_days: retention
                                 },
                                 keep_overrides: false
                             }))
@@ -759,6 +1027,51 @@ fn main() {
                         println!("Nothing with that ID exists");
                     }
                 },
+                "webhook" => {
+                    usage_min!(2, "webhook <\"create\"/\"delete\"/\"list\"> <...>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    macro_rules! find_channel {
+                        ($name:expr) => {
+                            match session.state.channels.values().find(|channel| channel.name == $name.trim_left_matches('#')) {
+                                Some(channel) => channel.id,
+                                None => {
+                                    println!("No channel found with that name");
+                                    continue;
+                                }
+                            }
+                        }
+                    }
+                    let packet = match &*args[0] {
+                        "create" => {
+                            usage!(3, "webhook create <channel> <url>");
+                            Packet::WebhookCreate(common::WebhookCreate {
+                                channel: find_channel!(args[1]),
+                                url: args.remove(2)
+                            })
+                        },
+                        "delete" => {
+                            usage!(2, "webhook delete <id>");
+                            match args[1].parse() {
+                                Ok(id) => Packet::WebhookDelete(common::WebhookDelete {
+                                    id: id
+                                }),
+                                Err(_) => {
+                                    println!("Not a valid number");
+                                    continue;
+                                }
+                            }
+                        },
+                        "list" => {
+                            usage!(2, "webhook list <channel>");
+                            Packet::WebhookList(common::WebhookList {
+                                channel: find_channel!(args[1])
+                            })
+                        },
+                        _ => { println!("Unable to do that with a webhook"); continue; }
+                    };
+                    write!(session, packet, {})
+                },
                 _ => {
                     println!("Unknown command");
                 }
@@ -781,8 +1094,10 @@ fn main() {
 
             let packet = Packet::Command(common::Command {
                 args: args,
+                id: session.command_next,
                 recipient: recipient
             });
+            session.command_next += 1;
 
             write!(session, packet, {});
 
@@ -809,6 +1124,12 @@ fn main() {
                     text: String::from_utf8_lossy(&last.1).replace(find, replace).into_bytes()
                 })
             } else {
+                if let Some(ref limits) = session.limits {
+                    if input.len() > limits.message_max {
+                        println!("Too long, the server only takes up to {} bytes", limits.message_max);
+                        continue;
+                    }
+                }
                 screen.log_with_id(format!("{}: {}", connector.nick.read().unwrap(), input), LogEntryId::Sending);
                 Packet::MessageCreate(common::MessageCreate {
                     channel: channel,
@@ -869,6 +1190,9 @@ fn to_single_perm_string(bitmask: u8) -> String {
     if bitmask & common::PERM_MANAGE_MESSAGES == common::PERM_MANAGE_MESSAGES {
         result.push('m');
     }
+    if bitmask & common::PERM_MANAGE_BOTS == common::PERM_MANAGE_BOTS {
+        result.push('b');
+    }
 
     result
 }
@@ -888,6 +1212,7 @@ fn from_perm_string(input: &str, allow: &mut u8, deny: &mut u8) -> bool {
             'c' => common::PERM_MANAGE_CHANNELS,
             'g' => common::PERM_MANAGE_GROUPS,
             'm' => common::PERM_MANAGE_MESSAGES,
+            'b' => common::PERM_MANAGE_BOTS,
             ' ' => continue,
             _   => return false
         };
@@ -933,3 +1258,15 @@ fn get_typing_string<I, V>(mut people: I, len: usize) -> String
         _ => String::new()
     }
 }
+fn presence_string(presence: &common::Presence) -> String {
+    let status = match presence.status {
+        common::Status::Away => "away",
+        common::Status::Dnd => "do not disturb",
+        common::Status::Offline => return String::new(),
+        common::Status::Online => "online"
+    };
+    match presence.custom {
+        Some(ref custom) => format!(" [{}: {}]", status, custom),
+        None => format!(" [{}]", status)
+    }
+}
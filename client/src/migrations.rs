@@ -0,0 +1,20 @@
+/// Every change ever made to the schema, in order.
+/// `PRAGMA user_version` is the number of them a database has had applied.
+/// Never edit one that's been released, add a new one instead.
+pub const MIGRATIONS: &[&str] = &[
+    // 1: Everything from before there were migrations
+    "CREATE TABLE IF NOT EXISTS data (
+        key     TEXT NOT NULL UNIQUE,
+        value   TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS pms (
+        private     BLOB NOT NULL,
+        public      BLOB NOT NULL,
+        recipient   INTEGER NOT NULL PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS servers (
+        ip      TEXT NOT NULL PRIMARY KEY,
+        key     BLOB NOT NULL,
+        token   TEXT
+    );"
+];
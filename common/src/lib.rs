@@ -14,24 +14,38 @@ pub const LIMIT_CHANNEL_NAME: usize = 128;
 pub const LIMIT_GROUP_NAME:   usize = 128;
 pub const LIMIT_GROUP_AMOUNT: usize = 2048;
 pub const LIMIT_MESSAGE:      usize = 16384;
+pub const LIMIT_COMMAND_AMOUNT: usize = 256;
+pub const LIMIT_STATUS:       usize = 128;
 
 pub const LIMIT_BULK:         usize = 64;
 
+// These are part of the protocol, so never renumber them. New codes go at the end.
 pub const ERR_GROUP_INVALID_POS:   u8 = 1;
 pub const ERR_GROUP_LOCKED_NAME:   u8 = 2;
-pub const ERR_LIMIT_REACHED:      u8 = 3;
-pub const ERR_LOGIN_BANNED:       u8 = 4;
-pub const ERR_LOGIN_BOT:          u8 = 5;
-pub const ERR_LOGIN_INVALID:      u8 = 6;
-pub const ERR_MAX_CONN_PER_IP:    u8 = 7;
-pub const ERR_MISSING_FIELD:      u8 = 8;
-pub const ERR_MISSING_PERMISSION: u8 = 9;
-pub const ERR_NAME_TAKEN:         u8 = 10;
-pub const ERR_UNKNOWN_BOT:        u8 = 11;
-pub const ERR_UNKNOWN_CHANNEL:    u8 = 12;
-pub const ERR_UNKNOWN_GROUP:      u8 = 13;
-pub const ERR_UNKNOWN_MESSAGE:    u8 = 14;
-pub const ERR_UNKNOWN_USER:       u8 = 15;
+pub const ERR_LIMIT_REACHED:       u8 = 3;
+pub const ERR_LOGIN_BANNED:        u8 = 4;
+pub const ERR_LOGIN_BOT:           u8 = 5;
+pub const ERR_LOGIN_INVALID:       u8 = 6;
+pub const ERR_MAX_CONN_PER_IP:     u8 = 7;
+pub const ERR_MISSING_FIELD:       u8 = 8;
+pub const ERR_MISSING_PERMISSION:  u8 = 9;
+pub const ERR_NAME_TAKEN:          u8 = 10;
+pub const ERR_UNKNOWN_BOT:         u8 = 11;
+pub const ERR_UNKNOWN_CHANNEL:     u8 = 12;
+pub const ERR_UNKNOWN_GROUP:       u8 = 13;
+pub const ERR_UNKNOWN_MESSAGE:     u8 = 14;
+pub const ERR_UNKNOWN_USER:        u8 = 15;
+pub const ERR_INVITE_INVALID:      u8 = 16;
+pub const ERR_REGISTRATION_CLOSED: u8 = 17;
+pub const ERR_UNKNOWN_TOKEN:       u8 = 18;
+pub const ERR_TOTP_ENABLED:        u8 = 19;
+pub const ERR_TOTP_INVALID:        u8 = 20;
+pub const ERR_COMMAND_INVALID:     u8 = 21;
+pub const ERR_UNKNOWN_COMMAND:     u8 = 22;
+pub const ERR_UNKNOWN_WEBHOOK:     u8 = 23;
+pub const ERR_WEBHOOK_INVALID:     u8 = 24;
+pub const ERR_STATUS_INVALID:      u8 = 25;
+pub const ERR_BACKUP_FAILED:       u8 = 26;
 
 pub const PERM_READ:              u8 = 1;
 pub const PERM_WRITE:             u8 = 1 << 1;
@@ -41,13 +55,74 @@ pub const PERM_BAN:               u8 = 1 << 3;
 pub const PERM_MANAGE_CHANNELS:   u8 = 1 << 4;
 pub const PERM_MANAGE_GROUPS:     u8 = 1 << 5;
 pub const PERM_MANAGE_MESSAGES:   u8 = 1 << 6;
+pub const PERM_MANAGE_BOTS:       u8 = 1 << 7;
 
 // TYPES
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgKind {
+    /// The name of a channel, which the server replaces with its ID
+    Channel,
+    Integer,
+    /// All remaining words, joined by spaces. Must be the last argument.
+    Rest,
+    Text,
+    /// The name of a user, which the server replaces with its ID
+    User
+}
+impl Default for ArgKind {
+    fn default() -> Self {
+        ArgKind::Text
+    }
+}
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Away,
+    /// Do not disturb
+    Dnd,
+    /// Set by the server when the user's last session closes. Can't be picked.
+    Offline,
+    Online
+}
+impl Default for Status {
+    fn default() -> Self {
+        Status::Offline
+    }
+}
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    /// Every user is sent on login
+    Full,
+    /// Only users that are online or were recently active are sent on login.
+    /// Anybody else can be looked up with `UserFetch`.
+    Lazy
+}
+impl Default for SyncMode {
+    fn default() -> Self {
+        SyncMode::Full
+    }
+}
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Channel {
     pub id: usize,
     pub name: String,
-    pub overrides: HashMap<usize, (u8, u8)>
+    pub overrides: HashMap<usize, (u8, u8)>,
+    /// How many days messages are kept. `None` uses the server's default, and 0 keeps them forever.
+    pub retention_days: Option<u32>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandArg {
+    pub kind: ArgKind,
+    pub name: String,
+    pub optional: bool
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandInfo {
+    pub args: Vec<CommandArg>,
+    pub description: String,
+    pub name: String
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Group {
@@ -58,6 +133,41 @@ pub struct Group {
     pub pos: usize,
     pub unassignable: bool
 }
+/// Lets something outside post to one channel as a bot, and do nothing else
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct IncomingWebhook {
+    pub bot: usize,
+    pub channel: usize,
+    pub id: usize,
+    /// Only sent once, when it's created
+    pub token: Option<String>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub creator: usize,
+    pub expires: Option<i64>,
+    pub groups: Vec<usize>,
+    pub max_uses: Option<usize>,
+    pub uses: usize
+}
+/// The limits the server enforces, in bytes where it's about length.
+/// Bots may have their own rate limits.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Limits {
+    pub channel_name_max: usize,
+    pub channel_name_min: usize,
+    pub command_amount_max: usize,
+    pub group_amount_max: usize,
+    pub group_name_max: usize,
+    pub group_name_min: usize,
+    pub message_max: usize,
+    pub message_min: usize,
+    pub requests_cheap_per_10_seconds: u8,
+    pub requests_expensive_per_5_minutes: u8,
+    pub user_name_max: usize,
+    pub user_name_min: usize
+}
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Message {
     pub author: usize,
@@ -68,21 +178,63 @@ pub struct Message {
     pub timestamp_edit: Option<i64>
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Presence {
+    pub custom: Option<String>,
+    pub status: Status
+}
+/// Where a reconnecting client left off
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Resume {
+    pub last_seq: u64
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Token {
+    pub created: i64,
+    pub current: bool,
+    pub id: usize,
+    pub last_ip: String,
+    pub last_used: i64,
+    pub name: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct User {
     pub ban: bool,
     pub bot: bool,
     pub groups: Vec<usize>,
     pub id: usize,
-    pub name: String
+    pub name: String,
+    pub owner: Option<usize>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Webhook {
+    pub channel: usize,
+    pub id: usize,
+    pub secret: String,
+    pub url: String
 }
 
 // CLIENT PACKETS
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Close;
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BackupCreate;
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BotCreate {
+    pub name: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BotDelete {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BotTokenReset {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ChannelCreate {
     pub name: String,
-    pub overrides: HashMap<usize, (u8, u8)>
+    pub overrides: HashMap<usize, (u8, u8)>,
+    pub retention_days: Option<u32>
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ChannelDelete {
@@ -96,9 +248,21 @@ pub struct ChannelUpdate {
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Command {
     pub args: Vec<String>,
+    pub id: usize,
     pub recipient: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandRegister {
+    pub commands: Vec<CommandInfo>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandResponse {
+    /// Whether only the session that ran the command should see the response
+    pub ephemeral: bool,
+    pub id: usize,
+    pub text: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct GroupCreate {
     pub allow: u8,
     pub deny: u8,
@@ -115,11 +279,41 @@ pub struct GroupUpdate {
     pub inner: Group
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct IncomingWebhookCreate {
+    pub bot: usize,
+    pub channel: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct IncomingWebhookDelete {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct IncomingWebhookList {
+    pub channel: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InviteCreate {
+    pub expires: Option<i64>,
+    pub groups: Vec<usize>,
+    pub max_uses: Option<usize>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InviteRevoke {
+    pub code: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Login {
     pub bot: bool,
+    pub device: Option<String>,
+    /// Whether journaled packets should be wrapped in `EventReceive`, to be able to resume later
+    pub events: bool,
     pub name: String,
     pub password: Option<String>,
-    pub token: Option<String>
+    /// Only send what was missed since then, if the server still remembers
+    pub resume: Option<Resume>,
+    pub sync: SyncMode,
+    pub token: Option<String>,
+    pub totp: Option<String>
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct LoginUpdate {
@@ -155,23 +349,83 @@ pub struct MessageUpdate {
     pub text: Vec<u8>
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PresenceUpdate {
+    pub inner: Presence
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct PrivateMessage {
     pub text: Vec<u8>,
     pub recipient: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Register {
+    pub bot: bool,
+    pub device: Option<String>,
+    /// Same as `Login::events`
+    pub events: bool,
+    pub invite: Option<String>,
+    pub name: String,
+    pub password: String,
+    pub sync: SyncMode
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenList;
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenRevoke {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TotpConfirm {
+    pub code: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TotpDisable {
+    pub code: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TotpEnroll {
+    pub password: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Typing {
     pub channel: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UserFetch {
+    pub ids: Vec<usize>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserUpdate {
     pub ban: Option<bool>,
     pub groups: Option<Vec<usize>>,
     pub id: usize
 }
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WebhookCreate {
+    pub channel: usize,
+    pub url: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WebhookDelete {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WebhookList {
+    pub channel: usize
+}
 
 // SERVER PACKETS
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BackupReceive {
+    /// The file name of the backup, in the server's backup directory
+    pub name: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BotTokenReceive {
+    pub id: usize,
+    pub token: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ChannelDeleteReceive {
     pub inner: Channel
 }
@@ -180,9 +434,33 @@ pub struct ChannelReceive {
     pub inner: Channel
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandListReceive {
+    pub bot: usize,
+    pub commands: Vec<CommandInfo>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct CommandReceive {
     pub args: Vec<String>,
-    pub author: usize
+    pub author: usize,
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandResponseReceive {
+    pub author: usize,
+    pub ephemeral: bool,
+    pub id: usize,
+    pub text: String
+}
+/// Tells whoever ran a command that the bot didn't respond in time
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CommandTimeoutReceive {
+    pub id: usize
+}
+/// Anything that was saved to the event journal, so it can be resent to clients that missed it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EventReceive {
+    pub inner: Box<Packet>,
+    pub seq: u64
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct GroupDeleteReceive {
@@ -194,9 +472,26 @@ pub struct GroupReceive {
     pub new: bool
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct IncomingWebhookReceive {
+    pub inner: IncomingWebhook
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InviteReceive {
+    pub inner: Invite
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LimitsReceive {
+    pub inner: Limits
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LoginChallenge;
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct LoginSuccess {
-    pub created: bool,
     pub id: usize,
+    /// Whether the server will only send what was missed, instead of everything
+    pub resumed: bool,
+    /// The last event that happened before this
+    pub seq: u64,
     pub token: String
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -214,14 +509,45 @@ pub struct PMReceive {
     pub text: Vec<u8>
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PresenceReceive {
+    pub id: usize,
+    pub inner: Presence
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ServerShutdown {
+    pub reason: String,
+    /// Roughly how many seconds until the server is expected to be back, if it is
+    pub reconnect_after: Option<u64>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenReceive {
+    pub inner: Token
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TotpRecoveryReceive {
+    pub codes: Vec<String>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TotpSecretReceive {
+    pub secret: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct TypingReceive {
     pub author: usize,
     pub channel: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UserDeleteReceive {
+    pub inner: User
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserReceive {
     pub inner: User
 }
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WebhookReceive {
+    pub inner: Webhook
+}
 
 macro_rules! packet {
     ($($type:ident),+) => {
@@ -264,7 +590,48 @@ packet! (
     MessageReceive,
     PMReceive,
     TypingReceive,
-    UserReceive
+    UserReceive,
+
+    // Packets are encoded by their position, so never reorder these either. New packets go at the end.
+    InviteCreate,
+    InviteRevoke,
+    InviteReceive,
+    Register,
+    TokenList,
+    TokenRevoke,
+    TokenReceive,
+    TotpConfirm,
+    TotpDisable,
+    TotpEnroll,
+    LoginChallenge,
+    TotpRecoveryReceive,
+    TotpSecretReceive,
+    CommandRegister,
+    CommandListReceive,
+    CommandResponse,
+    CommandResponseReceive,
+    CommandTimeoutReceive,
+    BotCreate,
+    BotDelete,
+    BotTokenReset,
+    BotTokenReceive,
+    UserDeleteReceive,
+    WebhookCreate,
+    WebhookDelete,
+    WebhookList,
+    WebhookReceive,
+    IncomingWebhookCreate,
+    IncomingWebhookDelete,
+    IncomingWebhookList,
+    IncomingWebhookReceive,
+    PresenceUpdate,
+    PresenceReceive,
+    ServerShutdown,
+    UserFetch,
+    EventReceive,
+    BackupCreate,
+    BackupReceive,
+    LimitsReceive
 );
 
 pub fn serialize(packet: &Packet) -> Result<Vec<u8>, rmps::encode::Error> {
@@ -328,6 +695,14 @@ pub fn read<T: io::Read>(reader: &mut T) -> Result<Packet, Error> {
     Ok(deserialize(&buf)?)
 }
 pub fn write<T: io::Write>(writer: &mut T, packet: &Packet) -> Result<(), Error> {
+    write_unflushed(writer, packet)?;
+    writer.flush()?;
+
+    Ok(())
+}
+/// Like `write`, but leaves flushing to the caller,
+/// so several packets can be sent in one go.
+pub fn write_unflushed<T: io::Write>(writer: &mut T, packet: &Packet) -> Result<(), Error> {
     let buf = serialize(packet)?;
     if buf.len() > std::u16::MAX as usize {
         return Err(Error::PacketTooBigError);
@@ -335,7 +710,6 @@ pub fn write<T: io::Write>(writer: &mut T, packet: &Packet) -> Result<(), Error>
     let size = encode_u16(buf.len() as u16);
     writer.write_all(&size)?;
     writer.write_all(&buf)?;
-    writer.flush()?;
 
     Ok(())
 }
@@ -0,0 +1,66 @@
+extern crate rusqlite;
+
+use rusqlite::Connection as SqlConnection;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    SqlError(rusqlite::Error),
+    /// The database was migrated by something newer, which knows about migrations this doesn't
+    TooNew(u32)
+}
+impl From<rusqlite::Error> for MigrationError {
+    fn from(err: rusqlite::Error) -> Self {
+        MigrationError::SqlError(err)
+    }
+}
+
+/// How many migrations `db` has had applied.
+/// This is stored in `PRAGMA user_version`.
+pub fn version(db: &SqlConnection) -> rusqlite::Result<u32> {
+    db.query_row("PRAGMA user_version", &[], |row| row.get::<_, i64>(0) as u32)
+}
+/// Applies every one of `migrations` that `db` is missing, each in its own transaction.
+/// Returns how many were applied.
+pub fn migrate(db: &mut SqlConnection, migrations: &[&str]) -> Result<usize, MigrationError> {
+    let current = version(db)?;
+    if current as usize > migrations.len() {
+        return Err(MigrationError::TooNew(current));
+    }
+    for (i, migration) in migrations.iter().enumerate().skip(current as usize) {
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        // PRAGMA doesn't take parameters
+        tx.execute_batch(&format!("PRAGMA user_version = {}", i + 1))?;
+        tx.commit()?;
+    }
+    Ok(migrations.len() - current as usize)
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    const MIGRATIONS: &[&str] = &[
+        "CREATE TABLE a (x INTEGER NOT NULL);",
+        "ALTER TABLE a ADD COLUMN y INTEGER NOT NULL DEFAULT 0;"
+    ];
+
+    let mut db = SqlConnection::open_in_memory().unwrap();
+    assert_eq!(migrate(&mut db, &MIGRATIONS[..1]).unwrap(), 1);
+    assert_eq!(migrate(&mut db, MIGRATIONS).unwrap(), 1);
+    assert_eq!(version(&db).unwrap(), 2);
+    assert_eq!(migrate(&mut db, MIGRATIONS).unwrap(), 0);
+    db.execute("INSERT INTO a (x, y) VALUES (1, 2)", &[]).unwrap();
+
+    // A failed migration is rolled back and not counted
+    match migrate(&mut db, &[MIGRATIONS[0], MIGRATIONS[1], "CREATE TABLE b (x); NOT SQL"]) {
+        Err(MigrationError::SqlError(_)) => (),
+        _ => panic!("a broken migration was applied")
+    }
+    assert_eq!(version(&db).unwrap(), 2);
+    assert!(db.execute("INSERT INTO b VALUES (1)", &[]).is_err());
+
+    match migrate(&mut db, &MIGRATIONS[..1]) {
+        Err(MigrationError::TooNew(version)) => assert_eq!(version, 2),
+        _ => panic!("a newer database was migrated")
+    }
+}
@@ -0,0 +1,287 @@
+use bcrypt;
+use ldap3::{LdapConn, Scope, SearchEntry};
+use rusqlite::Connection as SqlConnection;
+use std::collections::HashMap;
+use std::io;
+
+#[derive(Debug)]
+pub enum AuthError {
+    BcryptError(bcrypt::BcryptError),
+    IoError(io::Error),
+    Unsupported
+}
+impl From<bcrypt::BcryptError> for AuthError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        AuthError::BcryptError(err)
+    }
+}
+impl From<io::Error> for AuthError {
+    fn from(err: io::Error) -> Self {
+        AuthError::IoError(err)
+    }
+}
+
+/// What a provider knows about a user after a successful authentication.
+pub struct Identity {
+    /// The synac groups the user should be in, out of `managed`
+    pub groups: Vec<usize>,
+    /// The synac groups this provider is in charge of.
+    /// Membership of any other group is left alone.
+    pub managed: Vec<usize>,
+    /// Whether an account should be created if it doesn't exist yet
+    pub provision: bool
+}
+
+pub trait AuthProvider {
+    /// Checks the password of `name`. Returns `None` if the credentials are invalid.
+    fn authenticate(&self, db: &SqlConnection, name: &str, password: &str) -> Result<Option<Identity>, AuthError>;
+    /// Changes the password of the user with `id`.
+    fn change_password(&self, db: &SqlConnection, id: usize, password: &str) -> Result<(), AuthError>;
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum AuthConfig {
+    Sqlite,
+    Ldap(LdapConfig)
+}
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig::Sqlite
+    }
+}
+impl AuthConfig {
+    pub fn provider<'a>(&'a self) -> Box<AuthProvider + 'a> {
+        match *self {
+            AuthConfig::Sqlite => Box::new(SqliteProvider),
+            AuthConfig::Ldap(ref config) => Box::new(LdapProvider(config))
+        }
+    }
+    /// The provider in charge of the account called `name`.
+    /// Local accounts, like the owner's, keep using their own password after a directory is configured.
+    /// Only accounts the directory provisioned, or ones that don't exist yet, are left to it.
+    pub fn provider_for<'a>(&'a self, db: &SqlConnection, name: &str) -> Box<AuthProvider + 'a> {
+        let provisioned: Option<bool> = db.query_row(
+            "SELECT provisioned FROM users WHERE name = ?",
+            &[&name],
+            |row| row.get(0)
+        ).ok();
+        match provisioned {
+            Some(false) => Box::new(SqliteProvider),
+            _ => self.provider()
+        }
+    }
+}
+
+pub struct SqliteProvider;
+impl AuthProvider for SqliteProvider {
+    fn authenticate(&self, db: &SqlConnection, name: &str, password: &str) -> Result<Option<Identity>, AuthError> {
+        let mut stmt = db.prepare_cached("SELECT password FROM users WHERE name = ?").unwrap();
+        let mut rows = stmt.query(&[&name]).unwrap();
+
+        let hash: String = match rows.next() {
+            Some(row) => row.unwrap().get(0),
+            None => return Ok(None)
+        };
+        // Accounts created by other providers don't have a password
+        if hash.is_empty() {
+            return Ok(None);
+        }
+        if !bcrypt::verify(password, &hash)? {
+            return Ok(None);
+        }
+
+        Ok(Some(Identity {
+            groups: Vec::new(),
+            managed: Vec::new(),
+            provision: false
+        }))
+    }
+    fn change_password(&self, db: &SqlConnection, id: usize, password: &str) -> Result<(), AuthError> {
+        let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)?;
+        db.execute("UPDATE users SET password = ? WHERE id = ?", &[&hash, &(id as i64)]).unwrap();
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LdapConfig {
+    /// Binds send the password as is, so only use ldap:// on a trusted network
+    pub url: String,
+    /// The DN to bind as, where {} is replaced by the user name
+    pub user_dn: String,
+
+    pub group_attribute: String,
+    pub group_base: String,
+    /// The filter for finding a user's groups, where {} is replaced by the user's DN
+    pub group_filter: String,
+    /// Maps the `group_attribute` of directory groups to synac group IDs
+    pub groups: HashMap<String, usize>
+}
+impl Default for LdapConfig {
+    fn default() -> Self {
+        LdapConfig {
+            url: String::from("ldaps://localhost:636"),
+            user_dn: String::from("uid={},ou=people,dc=example,dc=com"),
+
+            group_attribute: String::from("cn"),
+            group_base: String::from("ou=groups,dc=example,dc=com"),
+            group_filter: String::from("(member={})"),
+            groups: HashMap::new()
+        }
+    }
+}
+
+pub struct LdapProvider<'a>(&'a LdapConfig);
+impl<'a> AuthProvider for LdapProvider<'a> {
+    fn authenticate(&self, _db: &SqlConnection, name: &str, password: &str) -> Result<Option<Identity>, AuthError> {
+        // Binding with an empty password is an "unauthenticated bind",
+        // which most servers happily accept for any DN.
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        let config = self.0;
+        let dn = config.user_dn.replace("{}", &escape_dn(name));
+        let ldap = LdapConn::new(&config.url)?;
+
+        if ldap.simple_bind(&dn, password)?.rc != 0 {
+            return Ok(None);
+        }
+
+        let filter = config.group_filter.replace("{}", &escape_filter(&dn));
+        let (entries, _) = ldap.search(
+            &config.group_base,
+            Scope::Subtree,
+            &filter,
+            vec![&*config.group_attribute]
+        )?.success()?;
+        let _ = ldap.unbind();
+
+        let mut groups = Vec::new();
+        for entry in entries {
+            let entry = SearchEntry::construct(entry);
+            for value in entry.attrs.get(&config.group_attribute).into_iter().flat_map(|values| values) {
+                if let Some(&id) = config.groups.get(value) {
+                    groups.push(id);
+                }
+            }
+        }
+
+        Ok(Some(Identity {
+            groups: groups,
+            managed: config.groups.values().cloned().collect(),
+            provision: true
+        }))
+    }
+    fn change_password(&self, _db: &SqlConnection, _id: usize, _password: &str) -> Result<(), AuthError> {
+        // Passwords are managed by the directory
+        Err(AuthError::Unsupported)
+    }
+}
+
+fn escape_dn(input: &str) -> String {
+    // RFC 4514, section 2.4
+    let mut output = String::with_capacity(input.len());
+    let last = input.chars().count().saturating_sub(1);
+
+    for (i, c) in input.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '=' | '>' | '\\' => output.push('\\'),
+            '#' | ' ' if i == 0 => output.push('\\'),
+            ' ' if i == last => output.push('\\'),
+            '\0' => { output.push_str("\\00"); continue; },
+            _ => {}
+        }
+        output.push(c);
+    }
+    output
+}
+fn escape_filter(input: &str) -> String {
+    // RFC 4515, section 3
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '*'  => output.push_str("\\2a"),
+            '('  => output.push_str("\\28"),
+            ')'  => output.push_str("\\29"),
+            '\\' => output.push_str("\\5c"),
+            '\0' => output.push_str("\\00"),
+            c => output.push(c)
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    assert_eq!(escape_dn("jd"), "jd");
+    assert_eq!(escape_dn("a,b=c"), "a\\,b\\=c");
+    assert_eq!(escape_dn(" #hi "), "\\ #hi\\ ");
+    assert_eq!(escape_dn("#hi"), "\\#hi");
+    assert_eq!(escape_filter("uid=*)(x"), "uid=\\2a\\29\\28x");
+}
+#[cfg(test)]
+#[test]
+fn test_local_fallback() {
+    use migrate;
+    use migrations::MIGRATIONS;
+
+    let mut db = SqlConnection::open_in_memory().unwrap();
+    migrate::migrate(&mut db, MIGRATIONS).unwrap();
+    let hash = bcrypt::hash("hunter2", 4).unwrap();
+    db.execute(
+        "INSERT INTO users (bot, last_ip, name, password, provisioned, token) VALUES (0, '', 'owner', ?, 0, '')",
+        &[&hash]
+    ).unwrap();
+    db.execute_batch("INSERT INTO users (bot, last_ip, name, password, provisioned, token)
+                      VALUES (0, '', 'jd', '', 1, '')").unwrap();
+
+    // Nothing listens here, so anything that goes to the directory fails
+    let config = AuthConfig::Ldap(LdapConfig {
+        url: String::from("ldap://127.0.0.1:1"),
+        ..LdapConfig::default()
+    });
+    let provider = config.provider_for(&db, "owner");
+    assert!(provider.authenticate(&db, "owner", "hunter2").unwrap().is_some());
+    assert!(provider.authenticate(&db, "owner", "wrong").unwrap().is_none());
+    provider.change_password(&db, 1, "hunter3").unwrap();
+
+    assert!(config.provider_for(&db, "jd").authenticate(&db, "jd", "hunter2").is_err());
+    assert!(config.provider_for(&db, "nobody").authenticate(&db, "nobody", "hunter2").is_err());
+}
+/// Needs a local slapd with `uid=jd,ou=people,dc=example,dc=com` (password "hunter2")
+/// and `cn=mods,ou=groups,dc=example,dc=com` with jd as a member.
+/// Run with `SYNAC_TEST_LDAP=ldaps://localhost:636 cargo test -- --ignored`.
+#[cfg(test)]
+#[test]
+#[ignore]
+fn test_slapd() {
+    use std::env;
+
+    let url = env::var("SYNAC_TEST_LDAP").expect("SYNAC_TEST_LDAP isn't set");
+    let mut groups = HashMap::new();
+    groups.insert(String::from("mods"), 3);
+    groups.insert(String::from("admins"), 4);
+    let config = LdapConfig {
+        url: url,
+        groups: groups,
+        ..LdapConfig::default()
+    };
+    let provider = LdapProvider(&config);
+    let db = SqlConnection::open_in_memory().unwrap();
+
+    let identity = provider.authenticate(&db, "jd", "hunter2").unwrap().expect("valid login was refused");
+    let mut managed = identity.managed;
+    managed.sort();
+    assert_eq!(identity.groups, vec![3]);
+    assert_eq!(managed, vec![3, 4]);
+    assert!(identity.provision);
+
+    assert!(provider.authenticate(&db, "jd", "hunter3").unwrap().is_none());
+    assert!(provider.authenticate(&db, "jd", "").unwrap().is_none());
+    assert!(provider.authenticate(&db, "nobody", "hunter2").unwrap().is_none());
+    assert!(provider.authenticate(&db, "*", "hunter2").unwrap().is_none());
+}
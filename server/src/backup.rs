@@ -0,0 +1,233 @@
+use chrono::Utc;
+use common;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{self, Connection as SqlConnection};
+use serde_json;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use storage::Storage;
+
+/// Bumped whenever `Export` changes in a way older servers can't read.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Everything worth moving to another server.
+/// Passwords, tokens and two-factor secrets are left out on purpose.
+#[derive(Deserialize, Serialize)]
+pub struct Export {
+    pub channels: Vec<common::Channel>,
+    pub groups: Vec<common::Group>,
+    pub messages: Vec<common::Message>,
+    pub users: Vec<common::User>,
+    pub version: u32
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    /// Something else had the database locked
+    Busy,
+    IoError(io::Error),
+    JsonError(serde_json::Error),
+    /// Imports only go into a database without any channels, messages or users
+    NotEmpty,
+    SqlError(rusqlite::Error),
+    /// The export was made by a newer server
+    TooNew(u32)
+}
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::IoError(err)
+    }
+}
+impl From<serde_json::Error> for BackupError {
+    fn from(err: serde_json::Error) -> Self {
+        BackupError::JsonError(err)
+    }
+}
+impl From<rusqlite::Error> for BackupError {
+    fn from(err: rusqlite::Error) -> Self {
+        BackupError::SqlError(err)
+    }
+}
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BackupError::Busy => write!(f, "the database is locked, try again"),
+            BackupError::IoError(ref err) => write!(f, "{}", err),
+            BackupError::JsonError(ref err) => write!(f, "{}", err),
+            BackupError::NotEmpty => write!(f, "the database already has data in it"),
+            BackupError::SqlError(ref err) => write!(f, "{}", err),
+            BackupError::TooNew(version) =>
+                write!(f, "the export is version {}, but this server only knows up to {}", version, EXPORT_VERSION)
+        }
+    }
+}
+
+/// Copies the whole database to `path` with SQLite's backup API,
+/// so the copy is consistent even if somebody's writing meanwhile.
+pub fn snapshot<P: AsRef<Path>>(db: &SqlConnection, path: P) -> Result<(), BackupError> {
+    let mut copy = SqlConnection::open(path)?;
+    {
+        let backup = Backup::new(db, &mut copy)?;
+        // Everything in one step, under one read transaction.
+        // Copying a few pages at a time starts over whenever somebody writes, which might be forever.
+        match backup.step(-1)? {
+            StepResult::Done => (),
+            _ => return Err(BackupError::Busy)
+        }
+    }
+    copy.close().map_err(|(_, err)| err)?;
+    Ok(())
+}
+/// Takes a snapshot into `dir`, named after the current time, and returns its path.
+/// Never overwrites an older snapshot, even one from the same second.
+pub fn snapshot_into<P: AsRef<Path>>(db: &SqlConnection, dir: P) -> Result<PathBuf, BackupError> {
+    fs::create_dir_all(dir.as_ref())?;
+    let time = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+    let mut path = dir.as_ref().join(format!("data-{}.sqlite", time));
+    let mut counter = 1;
+    // Creating the file claims the name, so two snapshots can't both pick it
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => break,
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => {
+                path = dir.as_ref().join(format!("data-{}-{}.sqlite", time, counter));
+                counter += 1;
+            },
+            Err(err) => return Err(err.into())
+        }
+    }
+    snapshot(db, &path)?;
+    Ok(path)
+}
+
+/// Collects everything in `db` worth exporting.
+pub fn export(db: &SqlConnection) -> Export {
+    let messages = {
+        let mut stmt = db.prepare_cached("SELECT * FROM messages ORDER BY id").unwrap();
+        let rows = stmt.query_map(&[], |row| super::get_message_by_fields(row)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
+    let users = {
+        let mut stmt = db.prepare_cached("SELECT * FROM users ORDER BY id").unwrap();
+        let rows = stmt.query_map(&[], |row| super::get_user_by_fields(db, row)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
+    Export {
+        channels: db.get_channels(),
+        groups: db.get_groups(),
+        messages: messages,
+        users: users,
+        version: EXPORT_VERSION
+    }
+}
+/// Writes `export(db)` to `path` as JSON.
+pub fn export_to<P: AsRef<Path>>(db: &SqlConnection, path: P) -> Result<(), BackupError> {
+    let file = File::create(path)?;
+    serde_json::to_writer(BufWriter::new(file), &export(db))?;
+    Ok(())
+}
+/// Fills an empty `db` with `export`, keeping every ID.
+/// Imported users have no password and no tokens, so they're left to the auth provider.
+pub fn import(db: &mut SqlConnection, export: &Export) -> Result<(), BackupError> {
+    if export.version > EXPORT_VERSION {
+        return Err(BackupError::TooNew(export.version));
+    }
+    let tx = db.transaction()?;
+
+    let count: i64 = tx.query_row(
+        "SELECT (SELECT COUNT(*) FROM channels) + (SELECT COUNT(*) FROM messages) + (SELECT COUNT(*) FROM users)",
+        &[],
+        |row| row.get(0)
+    )?;
+    if count != 0 {
+        return Err(BackupError::NotEmpty);
+    }
+
+    // Groups go first, since both memberships and overrides are only kept for groups that exist.
+    // The exported @humans and @bots replace the default ones.
+    for group in &export.groups {
+        tx.execute(
+            "INSERT OR REPLACE INTO groups (allow, deny, id, name, pos, unassignable) VALUES (?, ?, ?, ?, ?, ?)",
+            &[&group.allow, &group.deny, &(group.id as i64), &group.name, &(group.pos as i64), &group.unassignable]
+        )?;
+    }
+    for user in &export.users {
+        tx.execute(
+            "INSERT INTO users (ban, bot, id, last_ip, name, password, provisioned, token)
+            VALUES (?, ?, ?, '', ?, '', ?, '')",
+            &[&user.ban, &user.bot, &(user.id as i64), &user.name, &!user.bot]
+        )?;
+        if let Some(owner) = user.owner {
+            tx.execute("INSERT INTO bots (id, owner) VALUES (?, ?)", &[&(user.id as i64), &(owner as i64)])?;
+        }
+        tx.set_user_groups(user.id, &user.groups);
+    }
+    for channel in &export.channels {
+        tx.execute(
+            "INSERT INTO channels (id, name, retention_days) VALUES (?, ?, ?)",
+            &[&(channel.id as i64), &channel.name, &channel.retention_days.map(|days| days as i64)]
+        )?;
+        tx.insert_channel_overrides(channel.id, &channel.overrides);
+    }
+    for msg in &export.messages {
+        tx.execute(
+            "INSERT INTO messages (author, channel, id, text, timestamp, timestamp_edit) VALUES (?, ?, ?, ?, ?, ?)",
+            &[
+                &(msg.author as i64),
+                &(msg.channel as i64),
+                &(msg.id as i64),
+                &msg.text,
+                &msg.timestamp,
+                &msg.timestamp_edit
+            ]
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+/// Reads a JSON export from `path` and imports it into `db`.
+pub fn import_from<P: AsRef<Path>>(db: &mut SqlConnection, path: P) -> Result<(), BackupError> {
+    let file = File::open(path)?;
+    let export: Export = serde_json::from_reader(BufReader::new(file))?;
+    import(db, &export)
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    use migrate;
+    use migrations::MIGRATIONS;
+    use std::env;
+
+    let mut db = SqlConnection::open_in_memory().unwrap();
+    migrate::migrate(&mut db, MIGRATIONS).unwrap();
+    db.execute_batch("INSERT INTO channels (name) VALUES ('general');
+                      INSERT INTO groups VALUES (1, 0, 3, 'mods', 1, 0);
+                      INSERT INTO overrides VALUES (1, 1, 0, 3);
+                      INSERT INTO users (bot, last_ip, name, password, token) VALUES (0, '1.2.3.4', 'jd', 'hash', '');
+                      INSERT INTO user_groups VALUES (3, 1);
+                      INSERT INTO messages VALUES (1, 1, 1, 'hi', 0, NULL)").unwrap();
+
+    let json = serde_json::to_string(&export(&db)).unwrap();
+    assert!(!json.contains("hash") && !json.contains("1.2.3.4"));
+    assert!(match import(&mut db, &serde_json::from_str(&json).unwrap()) {
+        Err(BackupError::NotEmpty) => true,
+        _ => false
+    });
+
+    let mut copy = SqlConnection::open_in_memory().unwrap();
+    migrate::migrate(&mut copy, MIGRATIONS).unwrap();
+    import(&mut copy, &serde_json::from_str(&json).unwrap()).unwrap();
+    assert_eq!(serde_json::to_string(&export(&copy)).unwrap(), json);
+
+    let dir = env::temp_dir().join(format!("synac-backups-{}", ::std::process::id()));
+    let first = snapshot_into(&db, &dir).unwrap();
+    let second = snapshot_into(&db, &dir).unwrap();
+    assert_ne!(first, second);
+    let snapshot = SqlConnection::open(&first).unwrap();
+    assert_eq!(serde_json::to_string(&export(&snapshot)).unwrap(), json);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,91 @@
+use futures::sync::oneshot;
+use rusqlite::{self, Connection as SqlConnection};
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const BUSY_TIMEOUT_SECONDS: u64 = 5;
+
+/// Work for a database worker.
+/// This is a trait and not just a `Box<FnOnce>`, because those can't be called yet.
+pub trait Job: Send {
+    fn run(self: Box<Self>, db: &SqlConnection);
+}
+impl<F: FnOnce(&SqlConnection) + Send> Job for F {
+    fn run(self: Box<Self>, db: &SqlConnection) {
+        (*self)(db)
+    }
+}
+
+/// Opens the database the way every connection to it should be opened.
+pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<SqlConnection> {
+    let db = SqlConnection::open(path)?;
+    // Lets the workers read while somebody else is writing
+    db.query_row("PRAGMA journal_mode = WAL", &[], |_| ())?;
+    // SQLite leaves these off unless asked, every connection over again
+    db.execute_batch("PRAGMA foreign_keys = ON")?;
+    db.busy_timeout(Duration::from_secs(BUSY_TIMEOUT_SECONDS))?;
+    Ok(db)
+}
+
+/// A few threads with a connection each, for queries that shouldn't hold up everybody else.
+pub struct Pool {
+    sender: Mutex<Option<mpsc::Sender<Box<Job>>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>
+}
+impl Pool {
+    pub fn new<P: AsRef<Path>>(path: P, workers: usize) -> rusqlite::Result<Pool> {
+        let (sender, receiver) = mpsc::channel::<Box<Job>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut handles = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let db = open(path.as_ref())?;
+            let receiver = Arc::clone(&receiver);
+
+            handles.push(thread::spawn(move || {
+                loop {
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break
+                    };
+                    job.run(&db);
+                }
+                if let Err(err) = db.close() {
+                    eprintln!("Failed to close the database: {}", err);
+                }
+            }));
+        }
+
+        Ok(Pool {
+            sender: Mutex::new(Some(sender)),
+            workers: Mutex::new(handles)
+        })
+    }
+    /// Runs `job` on the first worker that's free.
+    pub fn execute<F: FnOnce(&SqlConnection) + Send + 'static>(&self, job: F) {
+        if let Some(ref sender) = *self.sender.lock().unwrap() {
+            let _ = sender.send(Box::new(job));
+        }
+    }
+    /// Like `execute`, but lets you wait for the result.
+    pub fn run<F, T>(&self, job: F) -> oneshot::Receiver<T>
+        where F: FnOnce(&SqlConnection) -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.execute(move |db: &SqlConnection| {
+            let _ = sender.send(job(db));
+        });
+        receiver
+    }
+    /// Finishes everything that's queued, and closes all connections.
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+        for worker in self.workers.lock().unwrap().drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use super::{Config, Event, Session, UserSession};
+use common;
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Stream};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{self, Body, Method, Request, Response, Server, StatusCode};
+use rusqlite::Connection as SqlConnection;
+use serde_json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use storage::Storage;
+use tokio;
+
+pub const LIMIT_BODY: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct IncomingMessage {
+    text: String
+}
+#[derive(Serialize)]
+struct IncomingReply {
+    id: usize
+}
+
+/// A `POST /messages`, waiting for the state thread to post it.
+pub(crate) struct Post {
+    pub(crate) body: Vec<u8>,
+    pub(crate) token: String
+}
+
+/// Accepts `POST /messages` with an incoming webhook's token,
+/// and posts the message as its bot in its channel.
+pub(crate) fn serve(addr: &SocketAddr, events: mpsc::UnboundedSender<Event>) -> Result<(), hyper::Error> {
+    let server = Server::try_bind(addr)?.serve(make_service_fn(move |_: &AddrStream| {
+        let events = events.clone();
+
+        future::ok::<_, hyper::Error>(service_fn(move |req| call(&events, req)))
+    }));
+
+    tokio::spawn(server.map_err(|err| eprintln!("Incoming webhook listener failed: {}", err)));
+    Ok(())
+}
+fn call(events: &mpsc::UnboundedSender<Event>, req: Request<Body>)
+    -> Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>
+{
+    macro_rules! status {
+        ($status:expr) => {
+            Response::builder().status($status).body(Body::empty()).unwrap()
+        }
+    }
+    if *req.method() != Method::POST || req.uri().path() != "/messages" {
+        return Box::new(future::ok(status!(StatusCode::NOT_FOUND)));
+    }
+    let token = req.headers().get(AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| if auth.starts_with("Bearer ") { Some(auth[7..].to_string()) } else { None });
+    let token = match token {
+        Some(token) => token,
+        None => return Box::new(future::ok(status!(StatusCode::UNAUTHORIZED)))
+    };
+
+    let events = events.clone();
+    let body = req.into_body()
+        .map_err(|_| StatusCode::BAD_REQUEST)
+        .fold(Vec::new(), |mut body, chunk| {
+            if body.len() + chunk.len() > LIMIT_BODY {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+    Box::new(body.and_then(move |body| {
+        let (sender, receiver) = oneshot::channel();
+        let post = Post {
+            body: body,
+            token: token
+        };
+        if events.unbounded_send(Event::Incoming(post, sender)).is_err() {
+            return future::Either::A(future::err(StatusCode::SERVICE_UNAVAILABLE));
+        }
+        future::Either::B(receiver.then(|result| match result {
+            Ok(result) => result,
+            Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE)
+        }))
+    }).then(|result| Ok(match result {
+        Ok(id) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&IncomingReply { id: id }).unwrap()))
+            .unwrap(),
+        Err(status) => status!(status)
+    })))
+}
+pub(crate) fn post(
+    config: &Config,
+    db: &SqlConnection,
+    post: Post,
+    sessions: &mut HashMap<usize, Session>,
+    token_key: &[u8],
+    users: &mut HashMap<usize, UserSession>
+) -> Result<usize, StatusCode> {
+    let hash = match super::hash_token(token_key, &post.token) {
+        Ok(ok) => ok,
+        Err(_) => {
+            eprintln!("Failed to hash token");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let row: Option<(i64, i64)> = db.query_row(
+        "SELECT bot, channel FROM incoming_webhooks WHERE token = ?",
+        &[&hash],
+        |row| (row.get(0), row.get(1))
+    ).ok();
+    let (id, channel) = row.ok_or(StatusCode::UNAUTHORIZED)?;
+    let id = id as usize;
+
+    let user = db.get_user(id).unwrap();
+    if !user.bot || user.ban {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let msg: IncomingMessage = serde_json::from_slice(&post.body).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let user_session = users.entry(id).or_insert_with(UserSession::new);
+    if super::check_rate_limits(config, false, user_session, id).is_some() {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    match super::create_message(config, db, id, channel as usize, msg.text.into_bytes()) {
+        Ok((channel, packet)) => {
+            let msg_id = match packet {
+                common::Packet::MessageReceive(ref event) => event.inner.id,
+                _ => unreachable!()
+            };
+            super::write_broadcast(Some(&channel.overrides), config, db, &packet, None, sessions);
+            Ok(msg_id)
+        },
+        Err(common::ERR_LIMIT_REACHED) => Err(StatusCode::BAD_REQUEST),
+        Err(common::ERR_MISSING_PERMISSION) => Err(StatusCode::FORBIDDEN),
+        Err(common::ERR_UNKNOWN_CHANNEL) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
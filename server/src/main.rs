@@ -2,35 +2,63 @@ extern crate bcrypt;
 extern crate chrono;
 extern crate common;
 extern crate futures;
+extern crate hyper;
+extern crate hyper_tls;
+extern crate ldap3;
+extern crate migrate;
+extern crate native_tls;
 extern crate openssl;
 extern crate rusqlite;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
-extern crate tokio_core;
-extern crate tokio_io;
+extern crate tokio;
 extern crate tokio_openssl;
+extern crate tokio_signal;
 
+use auth::AuthConfig;
 use common::Packet;
-use futures::{Future, Stream};
+use futures::sync::{mpsc, oneshot};
+use futures::future::{self, Loop, Shared};
+use futures::{Async, Future, Poll, Stream};
+use migrate::MigrationError;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
 use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
 use openssl::rand;
+use openssl::sign::Signer;
 use openssl::ssl::{SslMethod, SslAcceptorBuilder};
 use rusqlite::{Connection as SqlConnection, Row as SqlRow};
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::File;
-use std::io::{Read, Write, BufReader, BufWriter};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, BufReader};
+use std::mem;
 use std::net::{Ipv4Addr, IpAddr, SocketAddr};
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, Instant};
+use storage::Storage;
 use chrono::Utc;
-use tokio_core::net::{TcpListener, TcpStream};
-use tokio_core::reactor::{Core, Handle};
-use tokio_io::io;
+use hyper::StatusCode;
+use tokio::io::{self, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::AsyncRead;
+use tokio::runtime::Runtime;
+use tokio::timer::Interval;
 use tokio_openssl::{SslAcceptorExt, SslStream};
 
+mod auth;
+mod backup;
+mod db;
+mod incoming;
+mod migrations;
+mod storage;
+mod totp;
+mod webhooks;
+
 macro_rules! attempt_or {
     ($result:expr, $fail:block) => {
         match $result {
@@ -42,16 +70,64 @@ macro_rules! attempt_or {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Registration {
+    Open,
+    InviteOnly,
+    Closed
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+struct RateLimits {
+    requests_cheap_per_10_seconds: u8,
+    requests_expensive_per_5_minutes: u8
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 struct Config {
+    auth: AuthConfig,
+    /// Where `BackupCreate` puts its snapshots
+    backup_dir: String,
+    database_workers: usize,
+    /// How many events are remembered for clients that resume after a reconnect
+    journal_len: usize,
+    /// How many hours events are remembered for, at most
+    journal_max_age_hours: u64,
+    /// How many writes may wait for a connection before it's cut off.
+    /// Nothing is lost, since the client can reconnect and resume or sync again.
+    outbound_queue_len: usize,
     owner_id: usize,
-
+    registration: Registration,
+    /// How many days messages are kept in channels that don't say otherwise. `None` keeps them forever.
+    retention_days: Option<u32>,
+    shutdown_reason: String,
+    shutdown_reconnect_after: Option<u64>,
+    /// How many days since a user was last seen they're still sent to lazily syncing clients
+    sync_active_days: i64,
+    /// How many days a device can go without logging in before its token is forgotten. `None` keeps them forever.
+    token_expiry_days: Option<u32>,
+    /// Where incoming webhooks are accepted. They're plain HTTP,
+    /// so anything other than the local machine should go through a TLS proxy.
+    webhook_address: IpAddr,
+    /// Hosts outgoing webhooks may be sent to even though they aren't public, like `localhost`
+    webhook_allowed_hosts: Vec<String>,
+    webhook_port: Option<u16>,
+
+    limit_command_timeout_seconds: u64,
     limit_connections_per_ip: u32,
+    limit_login_backoff_max_seconds: u64,
+    limit_login_backoff_seconds: u64,
+    limit_login_failures_per_account: u32,
+    limit_login_failures_per_ip: u32,
+    limit_requests_bots: HashMap<usize, RateLimits>,
     limit_requests_cheap_per_10_seconds: u8,
     limit_requests_expensive_per_5_minutes: u8,
 
     limit_channel_name_max: usize,
     limit_channel_name_min: usize,
+    limit_command_amount_max: usize,
     limit_group_amount_max: usize,
     limit_group_name_max: usize,
     limit_group_name_min: usize,
@@ -60,62 +136,110 @@ struct Config {
     limit_user_name_max: usize,
     limit_user_name_min: usize
 }
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            auth: AuthConfig::default(),
+            backup_dir: String::from("backups"),
+            database_workers: 4,
+            journal_len: 10000,
+            journal_max_age_hours: 24,
+            outbound_queue_len: 512,
+            owner_id: 1,
+            registration: Registration::Open,
+            retention_days: None,
+            shutdown_reason: String::from("The server is shutting down"),
+            shutdown_reconnect_after: None,
+            sync_active_days: 7,
+            token_expiry_days: Some(90),
+            webhook_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            webhook_allowed_hosts: Vec::new(),
+            webhook_port: None,
+
+            limit_command_timeout_seconds: 10,
+            limit_connections_per_ip: 128,
+            limit_login_backoff_max_seconds: 60*60,
+            limit_login_backoff_seconds: 30,
+            limit_login_failures_per_account: 5,
+            limit_login_failures_per_ip: 20,
+            limit_requests_bots: HashMap::new(),
+            limit_requests_cheap_per_10_seconds: 7,
+            limit_requests_expensive_per_5_minutes: 2,
+
+            limit_channel_name_max: 32,
+            limit_channel_name_min: 1,
+            limit_command_amount_max: 32,
+            limit_group_amount_max: 128,
+            limit_group_name_max: 32,
+            limit_group_name_min: 1,
+            limit_message_max: 1024,
+            limit_message_min: 1,
+            limit_user_name_max: 32,
+            limit_user_name_min: 1
+        }
+    }
+}
 
 fn main() {
-    let db = attempt_or!(SqlConnection::open("data.sqlite"), {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // Lets upgrades be rolled out to the database before the new server is started
+    let migrate_only = args.iter().any(|arg| arg == "--migrate-only");
+    args.retain(|arg| arg != "--migrate-only");
+
+    let mut db = attempt_or!(db::open("data.sqlite"), {
         eprintln!("SQLite initialization failed.");
         eprintln!("Is the file corrupt?");
         eprintln!("Is the file permissions badly configured?");
         eprintln!("Just guessing here ¯\\_(ツ)_/¯");
         return;
     });
-    db.execute("CREATE TABLE IF NOT EXISTS channels (
-                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-                    name        TEXT NOT NULL
-                )", &[])
-        .expect("SQLite table creation failed");
-    db.execute("CREATE TABLE IF NOT EXISTS groups (
-                    allow   INTEGER NOT NULL,
-                    deny    INTEGER NOT NULL,
-                    id      INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-                    name    TEXT NOT NULL,
-                    pos     INTEGER NOT NULL,
-                    unassignable    INTEGER NOT NULL
-                )", &[])
-        .expect("SQLite table creation failed");
-    db.execute("INSERT OR IGNORE INTO groups VALUES (3, 0, 1, '@humans', 0, 1)", &[]).unwrap();
-    db.execute("INSERT OR IGNORE INTO groups VALUES (3, 0, 2, '@bots',   0, 1)", &[]).unwrap();
-    db.execute("CREATE TABLE IF NOT EXISTS messages (
-                    author      INTEGER NOT NULL,
-                    channel     INTEGER NOT NULL,
-                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-                    text        BLOB NOT NULL,
-                    timestamp   INTEGER NOT NULL,
-                    timestamp_edit  INTEGER
-                )", &[])
-        .expect("SQLite table creation failed");
-    db.execute("CREATE TABLE IF NOT EXISTS overrides (
-                    allow       INTEGER NOT NULL,
-                    channel     INTEGER NOT NULL,
-                    deny        INTEGER NOT NULL,
-                    [group]     INTEGER NOT NULL
-                )", &[])
-        .expect("SQLite table creation failed");
-    db.execute("CREATE TABLE IF NOT EXISTS users (
-                    ban         INTEGER NOT NULL DEFAULT 0,
-                    bot         INTEGER NOT NULL,
-                    groups      TEXT NOT NULL DEFAULT '',
-                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-                    last_ip     TEXT NOT NULL,
-                    name        TEXT NOT NULL COLLATE NOCASE,
-                    password    TEXT NOT NULL,
-                    token       TEXT NOT NULL
-                )", &[])
-        .expect("SQLite table creation failed");
-
-    let mut args = env::args();
-    args.next();
-    let port = args.next().map(|val| match val.parse() {
+    match migrate::migrate(&mut db, migrations::MIGRATIONS) {
+        Ok(0) => {},
+        Ok(applied) => println!("Applied {} database migration(s)", applied),
+        Err(MigrationError::TooNew(version)) => {
+            eprintln!("The database is at version {}, but this server only knows up to version {}.",
+                      version, migrations::MIGRATIONS.len());
+            eprintln!("Refusing to touch it. Did you downgrade?");
+            return;
+        },
+        Err(MigrationError::SqlError(err)) => {
+            eprintln!("Failed to migrate the database: {}", err);
+            return;
+        }
+    }
+    if migrate_only {
+        println!("The database is up to date");
+        return;
+    }
+
+    match args.first().map(|arg| &**arg) {
+        Some(command @ "backup") | Some(command @ "export") | Some(command @ "import") => {
+            let path = match args.get(1) {
+                Some(path) => path,
+                None => {
+                    eprintln!("Usage: server {} <file>", command);
+                    return;
+                }
+            };
+            let result = match command {
+                "backup" => backup::snapshot(&db, path),
+                "export" => backup::export_to(&db, path),
+                _ => backup::import_from(&mut db, path)
+            };
+            match result {
+                Ok(()) if command == "import" => {
+                    println!("Imported. Passwords and tokens aren't part of exports,");
+                    println!("so only users of an auth provider like LDAP can log in.");
+                },
+                Ok(()) => println!("Done"),
+                Err(err) => eprintln!("Failed to {}: {}", command, err)
+            }
+            return;
+        },
+        _ => {}
+    }
+
+    let port = args.first().map(|val| match val.parse() {
         Ok(ok) => ok,
         Err(_) => {
             eprintln!("Warning: Supplied port is not a valid number.");
@@ -176,161 +300,262 @@ fn main() {
     let config: Config;
     {
         let path = Path::new("optional-config.json");
+        if path.exists() {
+            config = match read_config(path) {
+                Some(config) => config,
+                None => return
+            };
+        } else {
+            config = Config::default();
+
+            match File::create(path) {
+                Ok(mut file) => if let Err(err) = serde_json::to_writer_pretty(&mut file, &config) {
+                    eprintln!("Failed to generate default config: {}", err);
+                },
+                Err(err) => eprintln!("Failed to create default config: {}", err)
+            }
+        }
+    }
+
+    let token_key = {
+        let path = Path::new("token-key");
+        let mut key = Vec::with_capacity(TOKEN_KEY_LEN);
         if path.exists() {
             let mut file = attempt_or!(File::open(path), {
-                eprintln!("Failed to open config");
+                eprintln!("Failed to open token key");
                 return;
             });
-            config = attempt_or!(serde_json::from_reader(&mut file), {
-                eprintln!("Failed to deserialize config");
+            attempt_or!(file.read_to_end(&mut key), {
+                eprintln!("Failed to read token key");
                 return;
             });
-            macro_rules! is_invalid {
-                ($min:ident, $max:ident, $hard_max:expr) => {
-                    config.$min > config.$max || config.$min == 0 || config.$max > $hard_max
-                }
-            }
-            if is_invalid!(limit_user_name_min, limit_user_name_max, common::LIMIT_USER_NAME)
-                || is_invalid!(limit_channel_name_min, limit_channel_name_max, common::LIMIT_CHANNEL_NAME)
-                || is_invalid!(limit_group_name_min, limit_group_name_max, common::LIMIT_GROUP_NAME)
-                || config.limit_group_amount_max > common::LIMIT_GROUP_AMOUNT
-                || is_invalid!(limit_message_min, limit_message_max, common::LIMIT_MESSAGE) {
-
-                eprintln!("Your config is exceeding a hard limit");
+            // A short key would make every token hash easy to forge
+            if key.len() != TOKEN_KEY_LEN {
+                eprintln!("The token key is {} bytes long, but it should be {}.", key.len(), TOKEN_KEY_LEN);
+                eprintln!("Is the file truncated? Remove it to generate a new one, which logs everybody out.");
                 return;
             }
         } else {
-            config = Config {
-                owner_id: 1,
-
-                limit_connections_per_ip: 128,
-                limit_requests_cheap_per_10_seconds: 7,
-                limit_requests_expensive_per_5_minutes: 2,
-
-                limit_channel_name_max: 32,
-                limit_channel_name_min: 1,
-                limit_group_amount_max: 128,
-                limit_group_name_max: 32,
-                limit_group_name_min: 1,
-                limit_message_max: 1024,
-                limit_message_min: 1,
-                limit_user_name_max: 32,
-                limit_user_name_min: 1
-            };
+            key.resize(TOKEN_KEY_LEN, 0);
+            rand::rand_bytes(&mut key).expect("Failed to generate token key");
 
-            match File::create(path) {
-                Ok(mut file) => if let Err(err) = serde_json::to_writer_pretty(&mut file, &config) {
-                    eprintln!("Failed to generate default config: {}", err);
-                },
-                Err(err) => eprintln!("Failed to create default config: {}", err)
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
             }
+            let mut file = attempt_or!(options.open(path), {
+                eprintln!("Failed to create token key");
+                return;
+            });
+            attempt_or!(file.write_all(&key), {
+                eprintln!("Failed to write token key");
+                return;
+            });
         }
-    }
+        key
+    };
 
-    let mut core = Core::new().expect("Could not start tokio core!");
-    let handle = core.handle();
-    let listener = attempt_or!(TcpListener::bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port), &handle), {
+    let pool = attempt_or!(db::Pool::new("data.sqlite", config.database_workers), {
+        eprintln!("Failed to open the database for the workers");
+        return;
+    });
+    let mut runtime = Runtime::new().expect("Could not start tokio runtime!");
+    let listener = attempt_or!(TcpListener::bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)), {
         eprintln!("An error occured when binding TCP listener!");
         eprintln!("Is the port in use?");
         return;
     });
     println!("Started connection on port {}", port);
 
-    let config   = Rc::new(config);
-    let conn_id  = Rc::new(RefCell::new(0usize));
-    let db       = Rc::new(db);
-    let handle   = Rc::new(handle);
-    let ips      = Rc::new(RefCell::new(HashMap::new()));
-    let sessions = Rc::new(RefCell::new(HashMap::new()));
-    let users    = Rc::new(RefCell::new(HashMap::new()));
+    let alive  = Arc::new(());
+    let config = Arc::new(config);
+    let pool   = Arc::new(pool);
+    let (events, receiver) = mpsc::unbounded();
 
-    println!("I'm alive!");
+    let state = {
+        let config = Arc::clone(&config);
+        let events = events.clone();
+        let pool = Arc::clone(&pool);
 
-    let server = listener.incoming().for_each(|(conn, addr)| {
-        use tokio_io::AsyncRead;
+        thread::spawn(move || run_state(config, db, events, pool, receiver, token_key))
+    };
+    // SIGHUP reloads the config, without dropping anybody
+    #[cfg(unix)]
+    {
+        use tokio_signal::unix::{Signal, SIGHUP};
+        let events = events.clone();
+        let reloads = Signal::new(SIGHUP).flatten_stream()
+            .map_err(|err| eprintln!("Failed to listen for SIGHUP: {}", err))
+            .for_each(move |_| {
+                println!("Reloading config...");
+                if let Some(config) = read_config(Path::new("optional-config.json")) {
+                    let _ = events.unbounded_send(Event::Reload(config));
+                }
+                Ok(())
+            });
+        runtime.spawn(reloads);
+    }
 
-        let config_clone   = Rc::clone(&config);
-        let conn_id_clone  = Rc::clone(&conn_id);
-        let db_clone       = Rc::clone(&db);
-        let handle_clone   = Rc::clone(&handle);
-        let ips_clone      = Rc::clone(&ips);
-        let sessions_clone = Rc::clone(&sessions);
-        let users_clone    = Rc::clone(&users);
+    if let Some(port) = config.webhook_port {
+        let addr = SocketAddr::new(config.webhook_address, port);
+        let events = events.clone();
+        let result = runtime.block_on(future::lazy(move || incoming::serve(&addr, events)));
+        attempt_or!(result, {
+            eprintln!("An error occured when binding the webhook listener!");
+            eprintln!("Is the port in use?");
+            return;
+        });
+        println!("Accepting incoming webhooks on {}", addr);
+    }
 
-        let accept = ssl.accept_async(conn).map_err(|_| ()).and_then(move |conn| {
-            let (reader, writer) = conn.split();
-            let reader = BufReader::new(reader);
-            let mut writer = BufWriter::new(writer);
+    println!("I'm alive!");
 
-            {
-                let mut ips = ips_clone.borrow_mut();
-                let conns = ips.entry(addr.ip()).or_insert(0);
-                if *conns >= config_clone.limit_connections_per_ip {
-                    write(&mut writer, Packet::Err(common::ERR_MAX_CONN_PER_IP));
-                }
-                *conns += 1;
+    {
+        let events = events.clone();
+        let sweeps = Interval::new(Instant::now(), Duration::from_secs(1))
+            .map_err(|_| ())
+            .for_each(move |_| events.unbounded_send(Event::Sweep).map_err(|_| ()));
+        runtime.spawn(sweeps);
+    }
+    {
+        let events = events.clone();
+        let prunes = Interval::new(Instant::now(), Duration::from_secs(PRUNE_INTERVAL_SECONDS))
+            .map_err(|_| ())
+            .for_each(move |_| events.unbounded_send(Event::Prune).map_err(|_| ()));
+        runtime.spawn(prunes);
+    }
+    {
+        let deliverer = match webhooks::Deliverer::new(Arc::clone(&pool), config.webhook_allowed_hosts.clone()) {
+            Some(some) => some,
+            None => {
+                eprintln!("Failed to initialize TLS for webhooks");
+                return;
             }
+        };
 
-            let my_conn_id = *conn_id_clone.borrow();
-            *conn_id_clone.borrow_mut() += 1;
+        let deliveries = Interval::new(Instant::now(), Duration::from_secs(1))
+            .map_err(|_| ())
+            .for_each(move |_| {
+                tokio::spawn(deliverer.deliver());
+                Ok(())
+            });
+        runtime.spawn(deliveries);
+    }
 
-            sessions_clone.borrow_mut().insert(my_conn_id, Session {
-                id: None,
-                writer: writer
+    let server = {
+        let alive = Arc::clone(&alive);
+        let events = events.clone();
+        let mut next_conn_id = 0;
+        let queue_len = config.outbound_queue_len;
+
+        listener.incoming().for_each(move |conn| {
+            let addr = conn.peer_addr()?;
+            let conn_id = next_conn_id;
+            next_conn_id += 1;
+
+            let alive = Arc::clone(&alive);
+            let events = events.clone();
+            let accept = ssl.accept_async(conn).map_err(|_| ()).and_then(move |conn| {
+                let (reader, writer) = conn.split();
+                let (sender, receiver) = mpsc::channel(queue_len);
+                let (kill, killed) = oneshot::channel();
+                let killed = killed.shared();
+
+                tokio::spawn(write_packets(alive, killed.clone(), receiver, writer));
+                let writer = Writer::new(kill, sender);
+                let _ = events.unbounded_send(Event::Connect(conn_id, addr.ip(), writer));
+                tokio::spawn(read_packets(conn_id, events, addr.ip(), killed, reader));
+                Ok(())
             });
+            tokio::spawn(accept);
+            Ok(())
+        })
+    };
 
-            handle_client(
-                config_clone,
-                my_conn_id,
-                db_clone,
-                &handle_clone,
-                addr.ip(),
-                ips_clone,
-                reader,
-                sessions_clone,
-                users_clone
-            );
+    // Ctrl+C, or SIGTERM from whatever is supervising the server
+    let signals = tokio_signal::ctrl_c().flatten_stream();
+    #[cfg(unix)]
+    let signals = {
+        use tokio_signal::unix::{Signal, SIGTERM};
+        signals.select(Signal::new(SIGTERM).flatten_stream().map(|_| ()))
+    };
+    let shutdown = signals.into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| err);
 
-            Ok(())
-        });
-        handle.spawn(accept);
-        Ok(())
-    });
+    runtime.block_on(server.select(shutdown))
+        .map(|_| ())
+        .map_err(|(err, _)| err)
+        .expect("Could not run tokio runtime!");
+
+    // The listener is gone now, so no new connections are accepted.
+    println!("Shutting down...");
 
-    core.run(server).expect("Could not run tokio core!");
+    let (done, finished) = oneshot::channel();
+    if events.unbounded_send(Event::Shutdown(done)).is_ok() {
+        let _ = finished.wait();
+    }
+    // Every session's writer is gone, so their tasks stop once they've sent what's left
+    let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_TIMEOUT_SECONDS);
+    while Arc::strong_count(&alive) > 1 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let _ = runtime.shutdown_now().wait();
+
+    // Both close their database connections when they're done
+    let _ = state.join();
+    pool.close();
 }
 
 pub const TOKEN_CHARS: &[u8; 62] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
 pub const RESERVED_ROLES: usize = 2;
-
+pub const COALESCE_LIMIT: usize = 64 * 1024;
+pub const PRUNE_BATCH: usize = 1024;
+pub const PRUNE_INTERVAL_SECONDS: u64 = 60;
+pub const SHUTDOWN_TIMEOUT_SECONDS: u64 = 5;
+pub const TOKEN_HASH_PREFIX: &str = "hmac-sha256$";
+pub const TOKEN_KEY_LEN: usize = 32;
+pub const TOTP_RECOVERY_CODES: usize = 10;
+pub const TOTP_RECOVERY_CODE_LEN: usize = 10;
+
+/// Applies the channel overrides that apply to somebody in `groups` on top of `perms`.
+fn apply_overrides(mut perms: u8, groups: &[usize], chan_overrides: &HashMap<usize, (u8, u8)>) -> u8 {
+    for (role, chan_perms) in chan_overrides {
+        if *role <= RESERVED_ROLES || groups.contains(role) {
+            common::perm_apply(&mut perms, *chan_perms);
+        }
+    }
+    perms
+}
+/// Tells everybody what the presence of `user` is now.
+fn broadcast_presence(config: &Config, db: &SqlConnection, sessions: &mut HashMap<usize, Session>, user: usize) {
+    let presence = get_presence(db, is_online(sessions, user), user);
+    write_broadcast(
+        None,
+        config,
+        db,
+        &Packet::PresenceReceive(common::PresenceReceive {
+            id: user,
+            inner: presence
+        }),
+        None,
+        sessions
+    );
+}
 fn calculate_permissions(
         db: &SqlConnection,
         bot: bool,
-        groups: &[usize],
+        user: usize,
         chan_overrides: Option<&HashMap<usize, (u8, u8)>>
     ) -> u8 {
-    let mut query = String::with_capacity(48+3+1+14);
-    query.push_str("SELECT allow, deny FROM groups WHERE id IN (");
-    query.push_str(if bot { "2" } else { "1" });
-    if !groups.is_empty() {
-        query.push_str(", ");
-        query.push_str(&from_list(groups));
-    }
-    query.push_str(") ORDER BY pos");
-
-    let mut stmt = db.prepare(&query).unwrap();
-    let rows = stmt.query_map(&[], |row| (row.get(0), row.get(1))).unwrap();
-    let mut rows = rows.map(|row| row.unwrap());
-
     let mut perms = 0;
-    common::perm_apply_iter(&mut perms, &mut rows);
+    common::perm_apply_iter(&mut perms, &mut db.get_user_permissions(bot, user).into_iter());
 
     if let Some(chan_overrides) = chan_overrides {
-        for (role, chan_perms) in chan_overrides {
-            if *role <= RESERVED_ROLES || groups.contains(role) {
-                common::perm_apply(&mut perms, *chan_perms);
-            }
-        }
+        perms = apply_overrides(perms, &db.get_user_groups(user), chan_overrides);
     }
 
     perms
@@ -340,30 +565,61 @@ fn calculate_permissions_by_user(
         id: usize,
         chan_overrides: Option<&HashMap<usize, (u8, u8)>>
     ) -> Option<u8> {
-    let mut stmt = db.prepare_cached("SELECT bot, groups FROM users WHERE id = ?").unwrap();
+    let mut stmt = db.prepare_cached("SELECT bot FROM users WHERE id = ?").unwrap();
     let mut rows = stmt.query(&[&(id as i64)]).unwrap();
 
     if let Some(row) = rows.next() {
         let row = row.unwrap();
-        // Yes I realize I could pass row.get(0) directly.
-        // However, what about SQL injections?
-        Some(calculate_permissions(db, row.get(0), &get_list(&row.get::<_, String>(1)), chan_overrides))
+        Some(calculate_permissions(db, row.get(0), id, chan_overrides))
+    } else {
+        None
+    }
+}
+/// Whether the journal still has every event after `last_seq`.
+fn can_resume(db: &SqlConnection, last_seq: u64) -> bool {
+    let latest = journal_seq(db);
+    if last_seq >= latest {
+        // Nothing was missed, unless the client is from the future
+        return last_seq == latest;
+    }
+    let oldest: Option<i64> = db.query_row("SELECT MIN(seq) FROM events", &[], |row| row.get(0)).unwrap();
+    oldest.map(|oldest| oldest as u64 <= last_seq + 1).unwrap_or(false)
+}
+fn check_login_backoff(config: &Config, failures: Option<&LoginFailures>, limit: u32) -> Option<u64> {
+    let failures = failures?;
+    if failures.amount < limit {
+        return None;
+    }
+
+    // Double the waiting time for every failure past the limit
+    let exponent = cmp::min(failures.amount - limit, 32);
+    let backoff = cmp::min(
+        config.limit_login_backoff_seconds.saturating_mul(1 << exponent),
+        config.limit_login_backoff_max_seconds
+    );
+    let elapsed = failures.last.elapsed().as_secs();
+    if elapsed < backoff {
+        Some(backoff - elapsed)
     } else {
         None
     }
 }
-fn check_rate_limits(config: &Config, expensive: bool, session: &mut UserSession) -> Option<u64> {
+fn check_rate_limits(config: &Config, expensive: bool, session: &mut UserSession, user: usize) -> Option<u64> {
+    let limits = config.limit_requests_bots.get(&user).cloned().unwrap_or(RateLimits {
+        requests_cheap_per_10_seconds: config.limit_requests_cheap_per_10_seconds,
+        requests_expensive_per_5_minutes: config.limit_requests_expensive_per_5_minutes
+    });
     let (duration, amount, packet_time, packets) = if expensive {
         (
             Duration::from_secs(60*5),
-            config.limit_requests_expensive_per_5_minutes,
+            limits.requests_expensive_per_5_minutes,
             &mut session.packet_time_expensive,
             &mut session.packets_expensive
         )
     } else {
         (
             Duration::from_secs(10),
-            config.limit_requests_cheap_per_10_seconds,
+            limits.requests_cheap_per_10_seconds,
             &mut session.packet_time_cheap,
             &mut session.packets_cheap
         )
@@ -383,6 +639,59 @@ fn check_rate_limits(config: &Config, expensive: bool, session: &mut UserSession
     }
     None
 }
+/// Checks and stores a new message, and queues it for webhooks.
+/// Returns the channel and the packet to broadcast.
+fn create_message(config: &Config, db: &SqlConnection, author: usize, channel: usize, text: Vec<u8>)
+        -> Result<(common::Channel, Packet), u8> {
+    if text.len() < config.limit_message_min
+        || text.len() > config.limit_message_max {
+        return Err(common::ERR_LIMIT_REACHED);
+    }
+
+    let channel = db.get_channel(channel).ok_or(common::ERR_UNKNOWN_CHANNEL)?;
+    let timestamp = Utc::now().timestamp();
+
+    if !has_perm(
+        config,
+        author,
+        calculate_permissions_by_user(db, author, Some(&channel.overrides)).unwrap(),
+        common::PERM_WRITE
+    ) {
+        return Err(common::ERR_MISSING_PERMISSION);
+    }
+
+    db.execute(
+        "INSERT INTO messages (author, channel, text, timestamp) VALUES (?, ?, ?, ?)",
+        &[&(author as i64), &(channel.id as i64), &text, &timestamp]
+    ).unwrap();
+
+    let packet = Packet::MessageReceive(common::MessageReceive {
+        inner: common::Message {
+            author: author,
+            channel: channel.id,
+            id: db.last_insert_rowid() as usize,
+            text: text,
+            timestamp: timestamp,
+            timestamp_edit: None
+        },
+        new: true
+    });
+    webhooks::queue(db, channel.id, &packet);
+    Ok((channel, packet))
+}
+fn create_token(db: &SqlConnection, token_key: &[u8], user: usize, name: &str, ip: &IpAddr)
+        -> Result<(usize, String), openssl::error::ErrorStack> {
+    let token = gen_token()?;
+    let hash = hash_token(token_key, &token)?;
+    let now = Utc::now().timestamp();
+
+    db.execute(
+        "INSERT INTO tokens (created, last_ip, last_used, name, token, user) VALUES (?, ?, ?, ?, ?, ?)",
+        &[&now, &ip.to_string(), &now, &name, &hash, &(user as i64)]
+    ).unwrap();
+
+    Ok((db.last_insert_rowid() as usize, token))
+}
 fn from_list(input: &[usize]) -> String {
     input.iter().fold(String::new(), |mut acc, item| {
         if !acc.is_empty() { acc.push(','); }
@@ -390,8 +699,8 @@ fn from_list(input: &[usize]) -> String {
         acc
     })
 }
-fn gen_token() -> Result<String, openssl::error::ErrorStack> {
-    let mut token = vec![0; 64];
+fn gen_random(len: usize) -> Result<String, openssl::error::ErrorStack> {
+    let mut token = vec![0; len];
     rand::rand_bytes(&mut token)?;
     for byte in &mut token {
         *byte = TOKEN_CHARS[*byte as usize % TOKEN_CHARS.len()];
@@ -399,44 +708,35 @@ fn gen_token() -> Result<String, openssl::error::ErrorStack> {
 
     Ok(unsafe { String::from_utf8_unchecked(token) })
 }
-fn get_channel(db: &SqlConnection, id: usize) -> Option<common::Channel> {
-    let mut stmt = db.prepare_cached("SELECT * FROM channels WHERE id = ?").unwrap();
-    let mut rows = stmt.query(&[&(id as i64)]).unwrap();
-    if let Some(row) = rows.next() {
-        let row = row.unwrap();
-        Some(get_channel_by_fields(db, &row))
-    } else {
-        None
-    }
+fn gen_token() -> Result<String, openssl::error::ErrorStack> {
+    gen_random(64)
 }
 fn get_channel_by_fields(db: &SqlConnection, row: &SqlRow) -> common::Channel {
-    let id = row.get::<_, i64>(0);
-
-    let mut stmt = db.prepare_cached("SELECT [group], allow, deny FROM overrides WHERE channel = ?").unwrap();
-    let mut rows = stmt.query(&[&id]).unwrap();
-
-    let mut overrides = HashMap::new();
-
-    while let Some(row) = rows.next() {
-        let row = row.unwrap();
-        overrides.insert(row.get::<_, i64>(0) as usize, (row.get(1), row.get(2)));
-    }
+    let id = row.get::<_, i64>(0) as usize;
 
     common::Channel {
-        id: id as usize,
+        id: id,
         name: row.get(1),
-        overrides: overrides
+        overrides: db.get_channel_overrides(id),
+        retention_days: row.get::<_, Option<i64>>(2).map(|days| days as u32)
     }
 }
-fn get_group(db: &SqlConnection, id: usize) -> Option<common::Group> {
-    let mut stmt = db.prepare_cached("SELECT * FROM groups WHERE id = ?").unwrap();
-    let mut rows = stmt.query(&[&(id as i64)]).unwrap();
-    if let Some(row) = rows.next() {
+fn get_commands(db: &SqlConnection, bot: usize) -> Vec<common::CommandInfo> {
+    let mut stmt = db.prepare_cached("SELECT args, description, name FROM commands WHERE bot = ? ORDER BY rowid")
+        .unwrap();
+    let mut rows = stmt.query(&[&(bot as i64)]).unwrap();
+
+    let mut commands = Vec::new();
+    while let Some(row) = rows.next() {
         let row = row.unwrap();
-        Some(get_group_by_fields(&row))
-    } else {
-        None
+        commands.push(common::CommandInfo {
+            args: serde_json::from_str(&row.get::<_, String>(0))
+                .expect("The database is broken. Congratz. You made me crash."),
+            description: row.get(1),
+            name: row.get(2)
+        });
     }
+    commands
 }
 fn get_group_by_fields(row: &SqlRow) -> common::Group {
     common::Group {
@@ -448,23 +748,30 @@ fn get_group_by_fields(row: &SqlRow) -> common::Group {
         unassignable: row.get(5)
     }
 }
+fn get_incoming_webhook_by_fields(row: &SqlRow) -> common::IncomingWebhook {
+    common::IncomingWebhook {
+        bot: row.get::<_, i64>(0) as usize,
+        channel: row.get::<_, i64>(1) as usize,
+        id: row.get::<_, i64>(2) as usize,
+        token: None
+    }
+}
+fn get_invite_by_fields(row: &SqlRow) -> common::Invite {
+    common::Invite {
+        code: row.get(0),
+        creator: row.get::<_, i64>(1) as usize,
+        expires: row.get(2),
+        groups: get_list(&row.get::<_, String>(3)),
+        max_uses: row.get::<_, Option<i64>>(4).map(|uses| uses as usize),
+        uses: row.get::<_, i64>(5) as usize
+    }
+}
 fn get_list(input: &str) -> Vec<usize> {
     input.split(',')
         .filter(|s| !s.is_empty())
         .map(|s| s.parse().expect("The database is broken. Congratz. You made me crash."))
         .collect()
 }
-fn get_message(db: &SqlConnection, id: usize) -> Option<common::Message> {
-    let mut stmt = db.prepare_cached("SELECT * FROM messages WHERE id = ?")
-        .unwrap();
-    let mut rows = stmt.query(&[&(id as i64)]).unwrap();
-    if let Some(row) = rows.next() {
-        let row = row.unwrap();
-        Some(get_message_by_fields(&row))
-    } else {
-        None
-    }
-}
 fn get_message_by_fields(row: &SqlRow) -> common::Message {
     common::Message {
         author: row.get::<_, i64>(0) as usize,
@@ -475,306 +782,1260 @@ fn get_message_by_fields(row: &SqlRow) -> common::Message {
         timestamp_edit: row.get(5)
     }
 }
-fn get_user(db: &SqlConnection, id: usize) -> Option<common::User> {
-    let mut stmt = db.prepare_cached("SELECT * FROM users WHERE id = ?").unwrap();
-    let mut rows = stmt.query(&[&(id as i64)]).unwrap();
+fn get_presence(db: &SqlConnection, online: bool, user: usize) -> common::Presence {
+    if !online {
+        return common::Presence::default();
+    }
+    let mut stmt = db.prepare_cached("SELECT custom, status FROM presence WHERE user = ?").unwrap();
+    let mut rows = stmt.query(&[&(user as i64)]).unwrap();
+
+    match rows.next() {
+        Some(row) => {
+            let row = row.unwrap();
+            let status: String = row.get(1);
+            common::Presence {
+                custom: row.get(0),
+                status: match &*status {
+                    "away" => common::Status::Away,
+                    "dnd" => common::Status::Dnd,
+                    _ => common::Status::Online
+                }
+            }
+        },
+        None => common::Presence {
+            custom: None,
+            status: common::Status::Online
+        }
+    }
+}
+fn get_token_by_fields(row: &SqlRow, current: Option<usize>) -> common::Token {
+    let id = row.get::<_, i64>(1) as usize;
+    common::Token {
+        created: row.get(0),
+        current: current == Some(id),
+        id: id,
+        last_ip: row.get(2),
+        last_used: row.get(3),
+        name: row.get(4)
+    }
+}
+fn get_user_by_fields(db: &SqlConnection, row: &SqlRow) -> common::User {
+    let id = row.get::<_, i64>(3);
+    let bot: bool = row.get(1);
 
-    if let Some(row) = rows.next() {
-        Some(get_user_by_fields(&row.unwrap()))
+    let owner = if bot {
+        db.query_row("SELECT owner FROM bots WHERE id = ?", &[&id], |row| row.get::<_, i64>(0) as usize).ok()
     } else {
         None
-    }
-}
-fn get_user_by_fields(row: &SqlRow) -> common::User {
+    };
+
     common::User {
         ban: row.get(0),
-        bot: row.get(1),
-        groups: get_list(&row.get::<_, String>(2)),
-        id: row.get::<_, i64>(3) as usize,
-        name: row.get(5)
+        bot: bot,
+        groups: db.get_user_groups(id as usize),
+        id: id as usize,
+        name: row.get(5),
+        owner: owner
     }
 }
-fn has_perm(config: &Config, user: usize, bitmask: u8, perm: u8) -> bool {
-    config.owner_id == user || bitmask & perm == perm
+fn get_webhook_by_fields(row: &SqlRow) -> common::Webhook {
+    common::Webhook {
+        channel: row.get::<_, i64>(0) as usize,
+        id: row.get::<_, i64>(1) as usize,
+        secret: row.get(2),
+        url: row.get(3)
+    }
 }
-fn insert_channel_overrides(db: &SqlConnection, channel: usize, overrides: &HashMap<usize, (u8, u8)>) {
-    db.execute("DELETE FROM overrides WHERE channel = ?", &[&(channel as i64)]).unwrap();
-
-    let mut stmt_exists = db.prepare_cached("SELECT COUNT(*) FROM groups WHERE id = ?") .unwrap();
-    let mut stmt_insert = db.prepare_cached("INSERT INTO overrides (allow, channel, deny, [group]) VALUES (?, ?, ?, ?)")
-        .unwrap();
-
-    for (id, &(allow, deny)) in overrides {
-        let count: i64 = stmt_exists.query_row(
-            &[&(*id as i64)],
+/// Whether somebody without `PERM_MANAGE_GROUPS` may hand out all of `groups`.
+/// None of them can be unassignable or at the bottom,
+/// and they all have to be below the highest group `user` is in.
+fn groups_assignable(db: &SqlConnection, groups: &[usize], user: usize) -> bool {
+    groups.iter().all(|&group| {
+        let count: i64 = db.query_row(
+            "SELECT COUNT(*) FROM groups WHERE id = ? AND unassignable = 0 AND pos != 0
+            AND (NOT EXISTS (SELECT 1 FROM user_groups WHERE user = ?)
+                OR pos < (SELECT MAX(pos) FROM groups WHERE id IN
+                    (SELECT [group] FROM user_groups WHERE user = ?)))",
+            &[&(group as i64), &(user as i64), &(user as i64)],
             |row| row.get(0)
         ).unwrap();
-        if count != 0 {
-            stmt_insert.execute(&[&allow, &(*id as i64), &(channel as i64), &deny]).unwrap();
-        }
+        count != 0
+    })
+}
+fn has_perm(config: &Config, user: usize, bitmask: u8, perm: u8) -> bool {
+    config.owner_id == user || bitmask & perm == perm
+}
+fn hash_token(token_key: &[u8], token: &str) -> Result<String, openssl::error::ErrorStack> {
+    let key = PKey::hmac(token_key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(token.as_bytes())?;
+
+    let mut hash = String::with_capacity(TOKEN_HASH_PREFIX.len() + 64);
+    hash.push_str(TOKEN_HASH_PREFIX);
+    for byte in &signer.sign_to_vec()? {
+        hash.push_str(&format!("{:02x}", byte));
     }
+    Ok(hash)
 }
-fn write<T: std::io::Write>(writer: &mut T, packet: Packet) -> bool {
-    attempt_or!(common::write(writer, &packet), {
-        eprintln!("Failed to send reply");
-        return false;
-    });
-    true
+fn is_online(sessions: &HashMap<usize, Session>, user: usize) -> bool {
+    sessions.values().any(|s| s.id == Some(user))
 }
-fn write_broadcast(
+/// Saves `packet` to the event journal, and returns its sequence number.
+/// Returns `None` for packets that are useless to anybody who missed them.
+fn journal(
     channel_overrides: Option<&HashMap<usize, (u8, u8)>>,
-    config: &Config,
     db: &SqlConnection,
     packet: &Packet,
-    recipient: Option<usize>,
-    sessions: &mut HashMap<usize, Session>
-) {
+    recipient: Option<usize>
+) -> Option<u64> {
+    if let Packet::TypingReceive(_) = *packet {
+        return None;
+    }
     let encoded = attempt_or!(common::serialize(packet), {
-        eprintln!("Failed to serialize message");
-        return;
-    });
-    assert!(encoded.len() <= std::u16::MAX as usize);
-    let size = common::encode_u16(encoded.len() as u16);
-    sessions.retain(|i, s| {
-        if let Some(id) = s.id {
-            // Check if the user really has permission to read this message.
-            if let Some(overrides) = channel_overrides {
-                if !has_perm(
-                    config,
-                    id,
-                    calculate_permissions_by_user(db, id, Some(overrides)).unwrap(),
-                    common::PERM_READ
-                ) {
-                    return true;
-                }
-            }
-            if let Some(recipient) = recipient {
-                if recipient != id {
-                    return true;
-                }
-            }
-
-            // Yes, I should be using io::write_all here - I very much agree.
-            // However, io::write_all takes a writer, not a reference to one (understandable).
-            // Sure, I could solve that with an Option (which I used to do).
-            // However, if two things would try to write at once we'd have issues...
-
-            match s.writer.write_all(&size)
-                .and_then(|_| s.writer.write_all(&encoded))
-                .and_then(|_| s.writer.flush()) {
-                Ok(ok) => ok,
-                Err(err) => {
-                    if err.kind() == std::io::ErrorKind::BrokenPipe {
-                        return false;
-                    } else {
-                        eprintln!("Failed to deliver message to connection #{}", i);
-                        eprintln!("Error kind: {}", err);
-                    }
-                }
-            }
-        }
-        true
+        eprintln!("Failed to serialize event");
+        return None;
     });
+    let overrides = channel_overrides.map(|overrides| serde_json::to_string(overrides).unwrap());
+    db.execute(
+        "INSERT INTO events (message, overrides, packet, recipient, timestamp) VALUES (?, ?, ?, ?, ?)",
+        &[
+            &message_of(packet).map(|id| id as i64),
+            &overrides,
+            &encoded,
+            &recipient.map(|recipient| recipient as i64),
+            &Utc::now().timestamp()
+        ]
+    ).unwrap();
+    Some(db.last_insert_rowid() as u64)
 }
-
-struct UserSession {
-    packet_time_cheap: Instant,
-    packet_time_expensive: Instant,
-    packets_cheap: usize,
-    packets_expensive: usize,
+/// The sequence number of the last event, even if it's been pruned since.
+fn journal_seq(db: &SqlConnection) -> u64 {
+    db.query_row(
+        "SELECT seq FROM sqlite_sequence WHERE name = 'events'",
+        &[],
+        |row| row.get::<_, i64>(0)
+    ).unwrap_or(0) as u64
 }
-struct Session {
-    id: Option<usize>,
-    writer: BufWriter<tokio_io::io::WriteHalf<SslStream<TcpStream>>>
+/// The limits from `config` clients are told about.
+fn limits(config: &Config) -> common::Limits {
+    common::Limits {
+        channel_name_max: config.limit_channel_name_max,
+        channel_name_min: config.limit_channel_name_min,
+        command_amount_max: config.limit_command_amount_max,
+        group_amount_max: config.limit_group_amount_max,
+        group_name_max: config.limit_group_name_max,
+        group_name_min: config.limit_group_name_min,
+        message_max: config.limit_message_max,
+        message_min: config.limit_message_min,
+        requests_cheap_per_10_seconds: config.limit_requests_cheap_per_10_seconds,
+        requests_expensive_per_5_minutes: config.limit_requests_expensive_per_5_minutes,
+        user_name_max: config.limit_user_name_max,
+        user_name_min: config.limit_user_name_min
+    }
 }
-impl UserSession {
-    fn new() -> UserSession {
-        UserSession {
-            packet_time_cheap: Instant::now(),
-            packet_time_expensive: Instant::now(),
-            packets_cheap: 0,
-            packets_expensive: 0
-        }
+/// The message whose text is in `packet`, if any.
+/// Lets the copies in the journal and the webhook queue be found once the message is gone.
+fn message_of(packet: &Packet) -> Option<usize> {
+    match *packet {
+        Packet::MessageReceive(ref event) => Some(event.inner.id),
+        _ => None
     }
 }
+/// Checks `args` against the commands `bot` has registered, and replaces channel and user names by IDs.
+/// Bots that never registered any commands accept anything.
+fn parse_command(db: &SqlConnection, bot: usize, mut args: Vec<String>) -> Result<Vec<String>, u8> {
+    let commands = get_commands(db, bot);
+    if commands.is_empty() {
+        return Ok(args);
+    }
+    let command = match args.first().and_then(|name| commands.iter().find(|command| command.name == *name)) {
+        Some(command) => command,
+        None => return Err(common::ERR_UNKNOWN_COMMAND)
+    };
 
-enum Reply {
-    // Send the message to all clients (optionally restricted to channel)
-    Broadcast(Option<HashMap<usize, (u8, u8)>>, Packet),
-    // Send the message to all clients with ID
-    Private(usize, Packet),
-    // Send initial packets like channels, groups, et.c
-    SendInitial(Box<Reply>),
+    let mut output = vec![args.remove(0)];
+    let mut input = args.into_iter();
 
-    Close,
-    None,
-    Reply(Packet),
-}
+    for arg in &command.args {
+        let value = if arg.kind == common::ArgKind::Rest {
+            let rest: Vec<_> = input.by_ref().collect();
+            if rest.is_empty() { None } else { Some(rest.join(" ")) }
+        } else {
+            input.next()
+        };
+        let value = match value {
+            Some(value) => value,
+            None if arg.optional => break,
+            None => return Err(common::ERR_COMMAND_INVALID)
+        };
 
-fn handle_client(
-        config:   Rc<Config>,
-        conn_id:  usize,
-        db:       Rc<SqlConnection>,
-        handle:   &Rc<Handle>,
-        ip:       IpAddr,
-        ips:      Rc<RefCell<HashMap<IpAddr, u32>>>,
-        reader:   BufReader<tokio_io::io::ReadHalf<SslStream<TcpStream>>>,
-        sessions: Rc<RefCell<HashMap<usize, Session>>>,
-        users:    Rc<RefCell<HashMap<usize, UserSession>>>
-    ) {
-    macro_rules! close {
-        () => {
-            sessions.borrow_mut().remove(&conn_id);
-            *ips.borrow_mut().get_mut(&ip).unwrap() -= 1;
-            return Ok(());
+        output.push(match arg.kind {
+            common::ArgKind::Channel => {
+                let name = if value.starts_with('#') { &value[1..] } else { &*value };
+                let id: i64 = db.query_row("SELECT id FROM channels WHERE name = ?", &[&name], |row| row.get(0))
+                    .map_err(|_| common::ERR_UNKNOWN_CHANNEL)?;
+                id.to_string()
+            },
+            common::ArgKind::Integer => if value.parse::<i64>().is_ok() {
+                value
+            } else {
+                return Err(common::ERR_COMMAND_INVALID);
+            },
+            common::ArgKind::Rest | common::ArgKind::Text => value,
+            common::ArgKind::User => {
+                let id: i64 = db.query_row("SELECT id FROM users WHERE name = ?", &[&value], |row| row.get(0))
+                    .map_err(|_| common::ERR_UNKNOWN_USER)?;
+                id.to_string()
+            }
+        });
+    }
+    if input.next().is_some() {
+        return Err(common::ERR_COMMAND_INVALID);
+    }
+
+    Ok(output)
+}
+/// Deletes messages older than their channel's retention, along with the copies of their text
+/// in the journal and the webhook queue. This is slow, so it's run on a worker.
+/// Returns the channels' overrides and what to broadcast about them, for the state thread.
+/// At most `PRUNE_BATCH` per channel at a time, so a big backlog doesn't hold everything else up.
+fn prune_messages(config: &Config, db: &SqlConnection) -> Vec<(HashMap<usize, (u8, u8)>, Vec<Packet>)> {
+    let now = Utc::now().timestamp();
+    let mut pruned = Vec::new();
+    // One transaction is a lot faster than one per channel, and nothing is left half pruned
+    db.execute_batch("SAVEPOINT prune_messages").unwrap();
+    for channel in db.get_channels() {
+        let days = match channel.retention_days.or(config.retention_days) {
+            None | Some(0) => continue,
+            Some(days) => days
+        };
+        let cutoff = now - days as i64 * 24 * 60 * 60;
+
+        let ids: Vec<usize> = {
+            let mut stmt = db.prepare_cached(
+                "SELECT id FROM messages WHERE channel = ? AND timestamp < ? ORDER BY timestamp LIMIT ?"
+            ).unwrap();
+            let rows = stmt.query_map(
+                &[&(channel.id as i64), &cutoff, &(PRUNE_BATCH as i64)],
+                |row| row.get::<_, i64>(0) as usize
+            ).unwrap();
+            rows.map(|row| row.unwrap()).collect()
+        };
+        if ids.is_empty() {
+            continue;
+        }
+        let list = from_list(&ids);
+        db.execute(&format!("DELETE FROM messages WHERE id IN ({})", list), &[]).unwrap();
+        db.execute(&format!("DELETE FROM events WHERE message IN ({})", list), &[]).unwrap();
+        db.execute(&format!("DELETE FROM webhook_queue WHERE message IN ({})", list), &[]).unwrap();
+
+        let packets: Vec<Packet> = ids.into_iter()
+            .map(|id| Packet::MessageDeleteReceive(common::MessageDeleteReceive { id: id }))
+            .collect();
+        for packet in &packets {
+            webhooks::queue(db, channel.id, packet);
+        }
+        pruned.push((channel.overrides, packets));
+    }
+    db.execute_batch("RELEASE prune_messages").unwrap();
+    pruned
+}
+/// Forgets tokens that haven't been used in `token_expiry_days`, so every login doesn't leave one behind forever.
+/// Bots only have the token they were given, and tokens that are still in use are kept.
+fn prune_tokens(config: &Config, db: &SqlConnection, sessions: &HashMap<usize, Session>) {
+    let days = match config.token_expiry_days {
+        None | Some(0) => return,
+        Some(days) => days
+    };
+    let cutoff = Utc::now().timestamp() - days as i64 * 24 * 60 * 60;
+    let in_use: Vec<usize> = sessions.values().filter_map(|s| s.token).collect();
+
+    let mut query = String::from(
+        "DELETE FROM tokens WHERE last_used < ?
+        AND user IN (SELECT id FROM users WHERE bot = 0)
+        AND id NOT IN ("
+    );
+    query.push_str(&from_list(&in_use));
+    query.push(')');
+
+    db.execute(&query, &[&cutoff]).unwrap();
+}
+/// Reads the config at `path`, and makes sure it's within the hard limits.
+/// Says what's wrong and returns `None` if it isn't usable.
+fn read_config(path: &Path) -> Option<Config> {
+    let mut file = attempt_or!(File::open(path), {
+        eprintln!("Failed to open config");
+        return None;
+    });
+    let config: Config = attempt_or!(serde_json::from_reader(&mut file), {
+        eprintln!("Failed to deserialize config");
+        return None;
+    });
+    macro_rules! is_invalid {
+        ($min:ident, $max:ident, $hard_max:expr) => {
+            config.$min > config.$max || config.$min == 0 || config.$max > $hard_max
         }
     }
+    if is_invalid!(limit_user_name_min, limit_user_name_max, common::LIMIT_USER_NAME)
+        || is_invalid!(limit_channel_name_min, limit_channel_name_max, common::LIMIT_CHANNEL_NAME)
+        || is_invalid!(limit_group_name_min, limit_group_name_max, common::LIMIT_GROUP_NAME)
+        || config.database_workers == 0
+        || config.outbound_queue_len == 0
+        || config.limit_command_amount_max > common::LIMIT_COMMAND_AMOUNT
+        || config.limit_group_amount_max > common::LIMIT_GROUP_AMOUNT
+        || config.limit_login_backoff_seconds == 0
+        || config.limit_login_backoff_max_seconds < config.limit_login_backoff_seconds
+        || config.limit_login_failures_per_account == 0
+        || config.limit_login_failures_per_ip == 0
+        || config.limit_requests_bots.values().any(|limits| {
+            limits.requests_cheap_per_10_seconds == 0 || limits.requests_expensive_per_5_minutes == 0
+        })
+        || is_invalid!(limit_message_min, limit_message_max, common::LIMIT_MESSAGE) {
 
-    let handle_clone = Rc::clone(handle);
-    let length = io::read_exact(reader, [0; 2])
-        .map_err(|_| ())
-        .and_then(move |(reader, bytes)| {
-            let size = common::decode_u16(&bytes) as usize;
+        eprintln!("Your config is exceeding a hard limit");
+        return None;
+    }
+    Some(config)
+}
+fn record_login_failure(config: &Config, logins: &mut LoginAttempts, ip: IpAddr, user: Option<usize>) {
+    let forget = Duration::from_secs(config.limit_login_backoff_max_seconds);
+    logins.ips.retain(|_, failures| failures.last.elapsed() < forget);
+    logins.users.retain(|_, failures| failures.last.elapsed() < forget);
+
+    logins.ips.entry(ip).or_insert_with(LoginFailures::new).add();
+    if let Some(user) = user {
+        logins.users.entry(user).or_insert_with(LoginFailures::new).add();
+    }
+}
+fn redeem_invite(db: &SqlConnection, code: &str) -> Option<Vec<usize>> {
+    let changed = db.execute(
+        "UPDATE invites SET uses = uses + 1 WHERE code = ?
+        AND (expires IS NULL OR expires > ?)
+        AND (max_uses IS NULL OR uses < max_uses)",
+        &[&code, &Utc::now().timestamp()]
+    ).unwrap();
+    if changed == 0 {
+        return None;
+    }
+
+    let groups: String = db.query_row(
+        "SELECT groups FROM invites WHERE code = ?",
+        &[&code],
+        |row| row.get(0)
+    ).unwrap();
+    Some(get_list(&groups))
+}
+/// Applies the groups an auth provider manages. Returns true if anything changed.
+fn sync_groups(db: &SqlConnection, user: usize, identity: &auth::Identity) -> bool {
+    let old = db.get_user(user).unwrap().groups;
+    let mut new: Vec<usize> = old.iter()
+        .cloned()
+        .filter(|group| !identity.managed.contains(group))
+        .collect();
+    for &group in &identity.groups {
+        if !new.contains(&group) && db.get_group(group).is_some() {
+            new.push(group);
+        }
+    }
 
-            if size == 0 {
-                close!();
+    if new == old {
+        return false;
+    }
+    db.set_user_groups(user, &new);
+    true
+}
+fn verify_token(hash: &str, stored: &str, token: &str) -> bool {
+    // Tokens created before hashing was introduced are still stored in plaintext.
+    // They're replaced by their hash as soon as they've been used once.
+    let expected = if stored.starts_with(TOKEN_HASH_PREFIX) { hash } else { token };
+
+    expected.len() == stored.len() && memcmp::eq(expected.as_bytes(), stored.as_bytes())
+}
+fn verify_totp(db: &SqlConnection, token_key: &[u8], user: usize, code: &str) -> bool {
+    let row: Option<(Vec<u8>, i64)> = db.query_row(
+        "SELECT secret, last_step FROM totp WHERE user = ? AND confirmed = 1",
+        &[&(user as i64)],
+        |row| (row.get(0), row.get(1))
+    ).ok();
+
+    if let Some((secret, last_step)) = row {
+        match totp::verify(&secret, code, Utc::now().timestamp()) {
+            // Codes can't be used twice
+            Ok(Some(step)) => if step > last_step {
+                db.execute(
+                    "UPDATE totp SET last_step = ? WHERE user = ?",
+                    &[&step, &(user as i64)]
+                ).unwrap();
+                return true;
+            } else {
+                return false;
+            },
+            Ok(None) => {},
+            Err(_) => {
+                eprintln!("Failed to verify TOTP code");
+                return false;
             }
+        }
+    }
 
-            let handle_clone_clone_ugh = Rc::clone(&handle_clone);
-            let lines = io::read_exact(reader, vec![0; size])
-                .map_err(|_| ())
-                .and_then(move |(reader, bytes)| {
-                    if !sessions.borrow().contains_key(&conn_id) {
+    let hash = attempt_or!(hash_token(token_key, code.trim()), {
+        eprintln!("Failed to hash recovery code");
+        return false;
+    });
+    let changed = db.execute(
+        "DELETE FROM recovery_codes WHERE user = ? AND code = ?",
+        &[&(user as i64), &hash]
+    ).unwrap();
+    changed != 0
+}
+fn write<T: std::io::Write>(writer: &mut T, packet: Packet) -> bool {
+    attempt_or!(common::write(writer, &packet), {
+        eprintln!("Failed to send reply");
+        return false;
+    });
+    true
+}
+/// Writes all `packets`, but only flushes once, so they go out together instead of one by one.
+fn write_batch<T: std::io::Write>(writer: &mut T, packets: Vec<Packet>) -> bool {
+    for packet in &packets {
+        attempt_or!(common::write_unflushed(writer, packet), {
+            eprintln!("Failed to send reply");
+            return false;
+        });
+    }
+    attempt_or!(writer.flush(), {
+        eprintln!("Failed to send reply");
+        return false;
+    });
+    true
+}
+fn write_broadcast(
+    channel_overrides: Option<&HashMap<usize, (u8, u8)>>,
+    config: &Config,
+    db: &SqlConnection,
+    packet: &Packet,
+    recipient: Option<usize>,
+    sessions: &mut HashMap<usize, Session>
+) {
+    write_broadcasts(channel_overrides, config, db, std::slice::from_ref(packet), recipient, sessions);
+}
+/// Like `write_broadcast`, but for several packets to the same people.
+/// Who may read them is only worked out once, and every connection gets them in one write.
+fn write_broadcasts(
+    channel_overrides: Option<&HashMap<usize, (u8, u8)>>,
+    config: &Config,
+    db: &SqlConnection,
+    packets: &[Packet],
+    recipient: Option<usize>,
+    sessions: &mut HashMap<usize, Session>
+) {
+    let encode = |packet: &Packet| -> Option<Vec<u8>> {
+        let encoded = attempt_or!(common::serialize(packet), {
+            eprintln!("Failed to serialize message");
+            return None;
+        });
+        assert!(encoded.len() <= std::u16::MAX as usize);
+        let mut frame = common::encode_u16(encoded.len() as u16).to_vec();
+        frame.extend_from_slice(&encoded);
+        Some(frame)
+    };
+    let mut plain = Vec::new();
+    let mut events = Vec::new();
+    // Journal them all in one transaction, instead of one per packet
+    db.execute_batch("SAVEPOINT write_broadcasts").unwrap();
+    for packet in packets {
+        let frame = match encode(packet) {
+            Some(frame) => frame,
+            None => continue
+        };
+        let event = match journal(channel_overrides, db, packet, recipient) {
+            Some(seq) => encode(&Packet::EventReceive(common::EventReceive {
+                inner: Box::new(packet.clone()),
+                seq: seq
+            })),
+            None => None
+        };
+        events.extend_from_slice(event.as_ref().unwrap_or(&frame));
+        plain.extend_from_slice(&frame);
+    }
+    db.execute_batch("RELEASE write_broadcasts").unwrap();
+    if plain.is_empty() {
+        return;
+    }
+    let mut dropped = Vec::new();
+    sessions.retain(|i, s| {
+        if let Some(id) = s.id {
+            // Check if the user really has permission to read this message.
+            if let Some(overrides) = channel_overrides {
+                if !has_perm(
+                    config,
+                    id,
+                    calculate_permissions_by_user(db, id, Some(overrides)).unwrap(),
+                    common::PERM_READ
+                ) {
+                    return true;
+                }
+            }
+            if let Some(recipient) = recipient {
+                if recipient != id {
+                    return true;
+                }
+            }
+
+            // This only queues the message. The connection's own task does the writing,
+            // so one slow client doesn't hold up everybody else.
+            let frame = if s.events { &events } else { &plain };
+            if let Some(ref mut held) = s.syncing {
+                held.extend_from_slice(frame);
+                return true;
+            }
+            if let Err(err) = s.writer.write_all(frame).and_then(|_| s.writer.flush()) {
+                if err.kind() != std::io::ErrorKind::BrokenPipe {
+                    eprintln!("Disconnecting connection #{}: {}", i, err);
+                }
+                dropped.extend(s.id);
+                return false;
+            }
+        }
+        true
+    });
+
+    dropped.sort();
+    dropped.dedup();
+    for user in dropped {
+        if !is_online(sessions, user) {
+            broadcast_presence(config, db, sessions, user);
+        }
+    }
+}
+
+/// Joins frames that are already queued up, so they're written in one go.
+/// Stops at `COALESCE_LIMIT` bytes, to not copy big batches around for nothing.
+struct Coalesce<S>(S);
+impl<S: Stream<Item = Vec<u8>>> Stream for Coalesce<S> {
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, S::Error> {
+        let mut buffer = match self.0.poll()? {
+            Async::Ready(Some(buffer)) => buffer,
+            Async::Ready(None) => return Ok(Async::Ready(None)),
+            Async::NotReady => return Ok(Async::NotReady)
+        };
+        while buffer.len() < COALESCE_LIMIT {
+            match self.0.poll()? {
+                Async::Ready(Some(more)) => buffer.extend_from_slice(&more),
+                // The end of the stream is noticed on the next poll
+                Async::Ready(None) | Async::NotReady => break
+            }
+        }
+        Ok(Async::Ready(Some(buffer)))
+    }
+}
+struct LoginFailures {
+    amount: u32,
+    last: Instant
+}
+#[derive(Default)]
+struct LoginAttempts {
+    ips: HashMap<IpAddr, LoginFailures>,
+    users: HashMap<usize, LoginFailures>
+}
+impl LoginFailures {
+    fn new() -> LoginFailures {
+        LoginFailures {
+            amount: 0,
+            last: Instant::now()
+        }
+    }
+    fn add(&mut self) {
+        self.amount += 1;
+        self.last = Instant::now();
+    }
+}
+struct PendingCommand {
+    author: usize,
+    bot: usize,
+    conn_id: usize,
+    id: usize,
+    /// Whether the author is told about a timeout.
+    /// Bots that never registered any commands might not respond at all.
+    notify: bool,
+    sent: Instant
+}
+#[derive(Default)]
+struct PendingCommands {
+    next: usize,
+    requests: HashMap<usize, PendingCommand>
+}
+struct UserSession {
+    packet_time_cheap: Instant,
+    packet_time_expensive: Instant,
+    packets_cheap: usize,
+    packets_expensive: usize,
+}
+struct Session {
+    /// Whether journaled packets are wrapped in `EventReceive`.
+    /// Only clients that want to resume later need them.
+    events: bool,
+    id: Option<usize>,
+    ip: IpAddr,
+    /// Broadcasts that arrived while the initial sync was being put together.
+    /// They're sent after it, or the older sync would undo them.
+    syncing: Option<Vec<u8>>,
+    token: Option<usize>,
+    writer: Writer
+}
+/// Everything connections share. Only the state thread ever touches it.
+struct State {
+    commands: PendingCommands,
+    ips: HashMap<IpAddr, u32>,
+    logins: LoginAttempts,
+    sessions: HashMap<usize, Session>,
+    users: HashMap<usize, UserSession>
+}
+/// Collects everything written until it's flushed,
+/// and then hands it over to the connection's writer task.
+struct Writer {
+    buffer: Vec<u8>,
+    kill: Option<oneshot::Sender<()>>,
+    sender: mpsc::Sender<Vec<u8>>
+}
+impl UserSession {
+    fn new() -> UserSession {
+        UserSession {
+            packet_time_cheap: Instant::now(),
+            packet_time_expensive: Instant::now(),
+            packets_cheap: 0,
+            packets_expensive: 0
+        }
+    }
+}
+impl Writer {
+    fn new(kill: oneshot::Sender<()>, sender: mpsc::Sender<Vec<u8>>) -> Writer {
+        Writer {
+            buffer: Vec::new(),
+            kill: Some(kill),
+            sender: sender
+        }
+    }
+}
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let buffer = mem::replace(&mut self.buffer, Vec::new());
+        match self.sender.try_send(buffer) {
+            Ok(()) => Ok(()),
+            // Dropping anything would leave the client out of sync, so it's cut off instead
+            Err(ref err) if err.is_full() => {
+                if let Some(kill) = self.kill.take() {
+                    let _ = kill.send(());
+                }
+                Err(std::io::Error::new(std::io::ErrorKind::Other, "outbound queue is full"))
+            },
+            // The writer task is gone, which means the connection is too
+            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+        }
+    }
+}
+
+/// Something for the state thread to do
+enum Event {
+    Connect(usize, IpAddr, Writer),
+    Disconnect(usize, IpAddr),
+    Incoming(incoming::Post, oneshot::Sender<Result<usize, StatusCode>>),
+    /// A packet from a connection, with the result of whatever it had a worker prepare.
+    /// It's told whether it should keep reading once it's handled.
+    Packet(usize, Packet, Option<Prepared>, oneshot::Sender<bool>),
+    /// Delete messages older than their channel's retention, and tokens nobody uses anymore
+    Prune,
+    /// Messages a worker pruned, to tell everybody who could read them about, per channel
+    Pruned(Vec<(HashMap<usize, (u8, u8)>, Vec<Packet>)>),
+    /// Swap in a new config, that's already been validated
+    Reload(Config),
+    /// The reply to a packet, once a worker is done with its query
+    Reply(usize, Reply, oneshot::Sender<bool>),
+    Shutdown(oneshot::Sender<()>),
+    /// Time out commands bots didn't respond to
+    Sweep
+}
+
+/// Work that only needs the database, and can be left to a worker.
+/// This is a trait and not just a `Box<FnOnce>`, because those can't be called yet.
+trait Query: Send {
+    fn run(self: Box<Self>, db: &SqlConnection) -> Reply;
+}
+impl<F: FnOnce(&SqlConnection) -> Reply + Send> Query for F {
+    fn run(self: Box<Self>, db: &SqlConnection) -> Reply {
+        (*self)(db)
+    }
+}
+/// Something slow a packet needs before it can be handled, like checking a password.
+/// A worker does it, and then the packet is handled again with the result.
+enum Prepare {
+    /// Check the password of a user, which can mean asking a directory server
+    Authenticate(AuthConfig, String, String),
+    /// Hash a new password
+    Hash(String)
+}
+impl Prepare {
+    fn run(self, db: &SqlConnection) -> Prepared {
+        match self {
+            Prepare::Authenticate(auth, name, password) =>
+                Prepared::Authenticated(auth.provider_for(db, &name).authenticate(db, &name, &password)),
+            Prepare::Hash(password) => Prepared::Hashed(bcrypt::hash(&password, bcrypt::DEFAULT_COST))
+        }
+    }
+}
+enum Prepared {
+    Authenticated(Result<Option<auth::Identity>, auth::AuthError>),
+    Hashed(Result<String, bcrypt::BcryptError>)
+}
+
+enum Reply {
+    // Send the message to all clients (optionally restricted to channel)
+    Broadcast(Option<HashMap<usize, (u8, u8)>>, Packet),
+    // Same as Broadcast, but with several messages
+    Broadcasts(Option<HashMap<usize, (u8, u8)>>, Vec<Packet>),
+    // Have a worker prepare the packet, and then handle it again
+    Prepare(Packet, Prepare),
+    // Send the message to all clients with ID
+    Private(usize, Packet),
+    // Run the query on a database worker, and then send whatever it replies
+    Query(Box<Query>),
+    // Send initial packets like channels, groups, et.c
+    SendInitial(common::SyncMode, Box<Reply>),
+    // The initial packets, followed by whatever was broadcasted while they were put together
+    Synced(Vec<Packet>),
+
+    Close,
+    None,
+    Replies(Vec<Packet>),
+    Reply(Packet),
+}
+
+/// Owns the state, and handles every event in order.
+/// Database work that doesn't need the state is passed on to the pool.
+fn run_state(
+    mut config: Arc<Config>,
+    db: SqlConnection,
+    events: mpsc::UnboundedSender<Event>,
+    pool: Arc<db::Pool>,
+    receiver: mpsc::UnboundedReceiver<Event>,
+    token_key: Vec<u8>
+) {
+    let mut state = State {
+        commands: PendingCommands::default(),
+        ips: HashMap::new(),
+        logins: LoginAttempts::default(),
+        sessions: HashMap::new(),
+        users: HashMap::new()
+    };
+    for event in receiver.wait() {
+        let event = match event {
+            Ok(event) => event,
+            Err(()) => break
+        };
+        match event {
+            Event::Connect(conn_id, ip, mut writer) => {
+                {
+                    let conns = state.ips.entry(ip).or_insert(0);
+                    if *conns >= config.limit_connections_per_ip {
+                        write(&mut writer, Packet::Err(common::ERR_MAX_CONN_PER_IP));
+                    }
+                    *conns += 1;
+                }
+
+                state.sessions.insert(conn_id, Session {
+                    events: false,
+                    id: None,
+                    ip: ip,
+                    syncing: None,
+                    token: None,
+                    writer: writer
+                });
+            },
+            Event::Disconnect(conn_id, ip) => {
+                close_session(&config, conn_id, &db, &mut state.sessions);
+                *state.ips.get_mut(&ip).unwrap() -= 1;
+            },
+            Event::Incoming(post, reply) => {
+                let _ = reply.send(incoming::post(
+                    &config,
+                    &db,
+                    post,
+                    &mut state.sessions,
+                    &token_key,
+                    &mut state.users
+                ));
+            },
+            Event::Packet(conn_id, packet, prepared, done) => {
+                let ip = match state.sessions.get(&conn_id) {
+                    Some(session) => session.ip,
+                    None => {
                         // Server wrongfully assumed client was dead after failed write.
                         // Well, too late now...
                         // ... or if the user is banned, since I abused this "feature"
-                        return Ok(());
+                        let _ = done.send(false);
+                        continue;
                     }
-                    let packet = match common::deserialize(&bytes) {
-                        Ok(ok) => ok,
-                        Err(err) => {
-                            eprintln!("Failed to deserialize message from client: {}", err);
-                            close!();
-                        }
-                    };
+                };
+                let reply = handle_packet(
+                    &mut state.commands,
+                    &config,
+                    conn_id,
+                    &db,
+                    &ip,
+                    &mut state.logins,
+                    packet,
+                    prepared,
+                    &mut state.sessions,
+                    &token_key,
+                    &mut state.users
+                );
+                send_reply(&config, conn_id, &db, done, &events, &pool, reply, &mut state.sessions);
+            },
+            Event::Reply(conn_id, reply, done) => {
+                send_reply(&config, conn_id, &db, done, &events, &pool, reply, &mut state.sessions);
+            },
+            Event::Shutdown(done) => {
+                let packet = Packet::ServerShutdown(common::ServerShutdown {
+                    reason: config.shutdown_reason.clone(),
+                    reconnect_after: config.shutdown_reconnect_after
+                });
+                for session in state.sessions.values_mut() {
+                    write(&mut session.writer, packet.clone());
+                }
+                // Dropping the writers lets their tasks finish
+                state.sessions.clear();
+                let _ = done.send(());
+                break;
+            },
+            Event::Prune => {
+                prune_tokens(&config, &db, &state.sessions);
+
+                let config = Arc::clone(&config);
+                let events = events.clone();
+                pool.execute(move |db: &SqlConnection| {
+                    let pruned = prune_messages(&config, db);
+                    if !pruned.is_empty() {
+                        let _ = events.unbounded_send(Event::Pruned(pruned));
+                    }
+                });
+            },
+            Event::Pruned(pruned) => for (overrides, packets) in pruned {
+                write_broadcasts(Some(&overrides), &config, &db, &packets, None, &mut state.sessions);
+            },
+            Event::Reload(new) => {
+                let restart_only = [
+                    ("database_workers", new.database_workers != config.database_workers),
+                    ("outbound_queue_len", new.outbound_queue_len != config.outbound_queue_len),
+                    ("webhook_address", new.webhook_address != config.webhook_address),
+                    ("webhook_allowed_hosts", new.webhook_allowed_hosts != config.webhook_allowed_hosts),
+                    ("webhook_port", new.webhook_port != config.webhook_port)
+                ];
+                for &(name, changed) in &restart_only {
+                    if changed {
+                        eprintln!("Warning: {} only changes on restart", name);
+                    }
+                }
 
-                    let mut send_init = false;
-                    let mut reply = handle_packet(
-                        &config,
-                        conn_id,
-                        &db,
-                        &ip,
-                        packet,
-                        &mut sessions.borrow_mut(),
-                        &mut users.borrow_mut()
-                    );
-
-                    if let Reply::SendInitial(inner) = reply {
-                        send_init = true;
-                        reply = *inner;
+                let old_limits = limits(&config);
+                // Everything after this sees the new config, nothing before it did
+                config = Arc::new(new);
+                let new_limits = limits(&config);
+                if new_limits != old_limits {
+                    let packet = Packet::LimitsReceive(common::LimitsReceive {
+                        inner: new_limits
+                    });
+                    write_broadcast(None, &config, &db, &packet, None, &mut state.sessions);
+                }
+                println!("Reloaded config");
+            },
+            Event::Sweep => {
+                db.execute(
+                    "DELETE FROM events WHERE seq <= ? OR timestamp < ?",
+                    &[
+                        &(journal_seq(&db) as i64 - config.journal_len as i64),
+                        &(Utc::now().timestamp() - config.journal_max_age_hours as i64 * 60 * 60)
+                    ]
+                ).unwrap();
+
+                let timeout = Duration::from_secs(config.limit_command_timeout_seconds);
+                let sessions = &mut state.sessions;
+                state.commands.requests.retain(|_, command| {
+                    if command.sent.elapsed() < timeout {
+                        return true;
+                    }
+                    if !command.notify {
+                        return false;
                     }
+                    if let Some(session) = sessions.get_mut(&command.conn_id) {
+                        write(&mut session.writer, Packet::CommandTimeoutReceive(common::CommandTimeoutReceive {
+                            id: command.id
+                        }));
+                    }
+                    false
+                });
+            }
+        }
+    }
 
-                    match reply {
-                        Reply::Broadcast(channel, packet) => {
-                            write_broadcast(
-                                channel.as_ref(),
-                                &config,
-                                &db,
-                                &packet,
-                                None,
-                                &mut sessions.borrow_mut()
-                            );
-                        },
-                        Reply::Private(recipient, packet) => {
-                            write_broadcast(
-                                None,
-                                &config,
-                                &db,
-                                &packet,
-                                Some(recipient),
-                                &mut sessions.borrow_mut()
-                            );
-                        },
-                        Reply::SendInitial(_) => unreachable!(),
-                        Reply::Close => { close!(); },
-                        Reply::None  => {},
-                        Reply::Reply(packet) => {
-                            let mut sessions = sessions.borrow_mut();
-                            let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
-
-                            write(writer, packet);
-                        }
+    if let Err(err) = db.close() {
+        eprintln!("Failed to close the database: {}", err);
+    }
+}
+/// Forgets about `conn_id`, and tells everybody if that was the last session of its user.
+fn close_session(config: &Config, conn_id: usize, db: &SqlConnection, sessions: &mut HashMap<usize, Session>) {
+    let user = sessions.remove(&conn_id).and_then(|s| s.id);
+    if let Some(user) = user {
+        if !is_online(sessions, user) {
+            broadcast_presence(config, db, sessions, user);
+        }
+    }
+}
+/// Tells a session it's logged in as `user`, and catches it up on whatever it missed.
+fn finish_login(
+    config: &Config,
+    conn_id: usize,
+    db: &SqlConnection,
+    events: bool,
+    online: bool,
+    resume: Option<common::Resume>,
+    sessions: &mut HashMap<usize, Session>,
+    sync: common::SyncMode,
+    token: String,
+    user: usize
+) -> Reply {
+    let events = events || resume.is_some();
+    let resume = resume.and_then(|resume| if can_resume(db, resume.last_seq) { Some(resume.last_seq) } else { None });
+    {
+        let session = sessions.get_mut(&conn_id).unwrap();
+        session.events = events;
+        write(&mut session.writer, Packet::LoginSuccess(common::LoginSuccess {
+            id: user,
+            resumed: resume.is_some(),
+            seq: journal_seq(db),
+            token: token
+        }));
+    }
+    // Catch up before anything new is broadcasted, so nothing arrives twice
+    if let Some(last_seq) = resume {
+        send_missed(config, conn_id, db, last_seq, sessions);
+    }
+    if !online {
+        broadcast_presence(config, db, sessions, user);
+    }
+    match resume {
+        Some(_) => Reply::None,
+        None => Reply::SendInitial(sync, Box::new(Reply::None))
+    }
+}
+/// Reads packets from a connection and hands them to the state thread, one at a time.
+fn read_packets(
+    conn_id: usize,
+    events: mpsc::UnboundedSender<Event>,
+    ip: IpAddr,
+    killed: Shared<oneshot::Receiver<()>>,
+    reader: ReadHalf<SslStream<TcpStream>>
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let events_clone = events.clone();
+    let reads = future::loop_fn(BufReader::new(reader), move |reader| {
+        let events = events.clone();
+        io::read_exact(reader, [0; 2])
+            .and_then(|(reader, bytes)| {
+                let size = common::decode_u16(&bytes) as usize;
+                io::read_exact(reader, vec![0; size])
+            })
+            .map_err(|_| ())
+            .and_then(move |(reader, bytes)| {
+                if bytes.is_empty() {
+                    return future::Either::A(future::ok(Loop::Break(())));
+                }
+                let packet = match common::deserialize(&bytes) {
+                    Ok(ok) => ok,
+                    Err(err) => {
+                        eprintln!("Failed to deserialize message from client: {}", err);
+                        return future::Either::A(future::ok(Loop::Break(())));
+                    }
+                };
+
+                let (done, keep_going) = oneshot::channel();
+                if events.unbounded_send(Event::Packet(conn_id, packet, None, done)).is_err() {
+                    return future::Either::A(future::ok(Loop::Break(())));
+                }
+                future::Either::B(keep_going.map_err(|_| ()).map(move |keep_going| {
+                    if keep_going {
+                        Loop::Continue(reader)
+                    } else {
+                        Loop::Break(())
                     }
+                }))
+            })
+    });
+    Box::new(reads.select2(when_killed(killed)).then(move |_| {
+        let _ = events_clone.unbounded_send(Event::Disconnect(conn_id, ip));
+        Ok::<(), ()>(())
+    }))
+}
+/// Sends everything a fresh session needs to know about the server.
+/// That can be every single user, so it's left to a worker.
+fn send_initial(config: &Config, sessions: &HashMap<usize, Session>, sync: common::SyncMode) -> Box<Query> {
+    let online: HashSet<usize> = sessions.values().filter_map(|s| s.id).collect();
+    let limits = limits(config);
+    let since = Utc::now().timestamp() - config.sync_active_days * 24 * 60 * 60;
+
+    Box::new(move |db: &SqlConnection| {
+        let mut packets = vec![Packet::LimitsReceive(common::LimitsReceive {
+            inner: limits
+        })];
+        for group in db.get_groups() {
+            packets.push(Packet::GroupReceive(common::GroupReceive {
+                inner: group,
+                new: false,
+            }));
+        }
+        for channel in db.get_channels() {
+            packets.push(Packet::ChannelReceive(common::ChannelReceive {
+                inner: channel,
+            }));
+        }
+        {
+            // A lazy sync only gets users that are recently active or have commands,
+            // and whoever else is online is added below
+            let lazy = sync == common::SyncMode::Lazy;
 
-                    if send_init {
-                        let mut sessions = sessions.borrow_mut();
-                        let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
-                        {
-                            let mut stmt = db.prepare_cached("SELECT * FROM groups").unwrap();
-                            let mut rows = stmt.query(&[]).unwrap();
+            let mut stmt = db.prepare_cached(
+                "SELECT * FROM users WHERE ? = 0
+                OR id IN (SELECT user FROM tokens WHERE last_used >= ?)
+                OR id IN (SELECT bot FROM commands)"
+            ).unwrap();
+            let mut rows = stmt.query(&[&lazy, &since]).unwrap();
 
-                            while let Some(row) = rows.next() {
-                                let row = row.unwrap();
+            let mut sent = HashSet::new();
+            while let Some(row) = rows.next() {
+                let row = row.unwrap();
+                let user = get_user_by_fields(db, &row);
+                sent.insert(user.id);
 
-                                write(writer, Packet::GroupReceive(common::GroupReceive {
-                                    inner: get_group_by_fields(&row),
-                                    new: false,
-                                }));
-                            }
-                        } {
-                            let mut stmt = db.prepare_cached("SELECT * FROM channels").unwrap();
-                            let mut rows = stmt.query(&[]).unwrap();
+                packets.extend(user_packets(db, &online, user));
+            }
+            for &id in online.difference(&sent) {
+                if let Some(user) = db.get_user(id) {
+                    packets.extend(user_packets(db, &online, user));
+                }
+            }
+        } {
+            let mut stmt = db.prepare_cached("SELECT DISTINCT bot FROM commands").unwrap();
+            let mut rows = stmt.query(&[]).unwrap();
 
-                            while let Some(row) = rows.next() {
-                                let row = row.unwrap();
+            while let Some(row) = rows.next() {
+                let bot = row.unwrap().get::<_, i64>(0) as usize;
 
-                                write(writer, Packet::ChannelReceive(common::ChannelReceive {
-                                    inner: get_channel_by_fields(&db, &row),
-                                }));
-                            }
-                        } {
-                            let mut stmt = db.prepare_cached("SELECT * FROM users").unwrap();
-                            let mut rows = stmt.query(&[]).unwrap();
+                packets.push(Packet::CommandListReceive(common::CommandListReceive {
+                    bot: bot,
+                    commands: get_commands(db, bot)
+                }));
+            }
+        }
 
-                            while let Some(row) = rows.next() {
-                                let row = row.unwrap();
+        Reply::Synced(packets)
+    })
+}
+/// Sends every journaled event after `last_seq` that the session's user may see.
+fn send_missed(
+    config: &Config,
+    conn_id: usize,
+    db: &SqlConnection,
+    last_seq: u64,
+    sessions: &mut HashMap<usize, Session>
+) {
+    let id = match sessions.get(&conn_id).and_then(|s| s.id) {
+        Some(id) => id,
+        None => return
+    };
+    // Only the overrides differ between events, so everything else is looked up once
+    let perms = calculate_permissions_by_user(db, id, None).unwrap();
+    let groups = db.get_user_groups(id);
 
-                                write(writer, Packet::UserReceive(common::UserReceive {
-                                    inner: get_user_by_fields(&row)
-                                }));
-                            }
-                        }
-                    }
+    let mut packets = Vec::new();
+    {
+        let mut stmt = db.prepare_cached(
+            "SELECT overrides, packet, recipient, seq FROM events WHERE seq > ? ORDER BY seq"
+        ).unwrap();
+        let mut rows = stmt.query(&[&(last_seq as i64)]).unwrap();
 
-                    handle_client(
-                        config,
-                        conn_id,
-                        db,
-                        &handle_clone_clone_ugh,
-                        ip,
-                        ips,
-                        reader,
-                        sessions,
-                        users
-                    );
-
-                    Ok(())
-                });
+        while let Some(row) = rows.next() {
+            let row = row.unwrap();
 
-            handle_clone.spawn(lines);
-            Ok(())
-        });
+            if let Some(recipient) = row.get::<_, Option<i64>>(2) {
+                if recipient as usize != id {
+                    continue;
+                }
+            }
+            if let Some(overrides) = row.get::<_, Option<String>>(0) {
+                let overrides: HashMap<usize, (u8, u8)> = serde_json::from_str(&overrides).unwrap();
+                if !has_perm(config, id, apply_overrides(perms, &groups, &overrides), common::PERM_READ) {
+                    continue;
+                }
+            }
+            let packet = attempt_or!(common::deserialize(&row.get::<_, Vec<u8>>(1)), {
+                eprintln!("Failed to deserialize event");
+                continue;
+            });
+            packets.push(Packet::EventReceive(common::EventReceive {
+                inner: Box::new(packet),
+                seq: row.get::<_, i64>(3) as u64
+            }));
+        }
+    }
+
+    write_batch(&mut sessions.get_mut(&conn_id).unwrap().writer, packets);
+}
+/// Sends `reply` wherever it should go, and tells the reader of `conn_id` whether to keep going.
+fn send_reply(
+    config: &Config,
+    conn_id: usize,
+    db: &SqlConnection,
+    done: oneshot::Sender<bool>,
+    events: &mpsc::UnboundedSender<Event>,
+    pool: &db::Pool,
+    mut reply: Reply,
+    sessions: &mut HashMap<usize, Session>
+) {
+    let mut send_init = None;
+    if let Reply::SendInitial(sync, inner) = reply {
+        send_init = Some(sync);
+        reply = *inner;
+    }
+
+    match reply {
+        Reply::Broadcast(channel, packet) => {
+            write_broadcast(channel.as_ref(), config, db, &packet, None, sessions);
+        },
+        Reply::Broadcasts(channel, packets) => {
+            write_broadcasts(channel.as_ref(), config, db, &packets, None, sessions);
+        },
+        Reply::Prepare(packet, prepare) => {
+            let events = events.clone();
+            pool.execute(move |db: &SqlConnection| {
+                let prepared = prepare.run(db);
+                let _ = events.unbounded_send(Event::Packet(conn_id, packet, Some(prepared), done));
+            });
+            return;
+        },
+        Reply::Private(recipient, packet) => {
+            write_broadcast(None, config, db, &packet, Some(recipient), sessions);
+        },
+        Reply::Query(query) => {
+            let events = events.clone();
+            pool.execute(move |db: &SqlConnection| {
+                let _ = events.unbounded_send(Event::Reply(conn_id, query.run(db), done));
+            });
+            return;
+        },
+        Reply::SendInitial(..) => unreachable!(),
+        Reply::Close => {
+            close_session(config, conn_id, db, sessions);
+            let _ = done.send(false);
+            return;
+        },
+        Reply::None => {},
+        Reply::Replies(packets) => if let Some(session) = sessions.get_mut(&conn_id) {
+            write_batch(&mut session.writer, packets);
+        },
+        Reply::Synced(packets) => {
+            let failed = match sessions.get_mut(&conn_id) {
+                Some(session) => {
+                    let held = session.syncing.take().unwrap_or_default();
+                    write_batch(&mut session.writer, packets);
+                    session.writer.write_all(&held).and_then(|_| session.writer.flush()).is_err()
+                },
+                None => false
+            };
+            if failed {
+                close_session(config, conn_id, db, sessions);
+                let _ = done.send(false);
+                return;
+            }
+        },
+        Reply::Reply(packet) => if let Some(session) = sessions.get_mut(&conn_id) {
+            write(&mut session.writer, packet);
+        }
+    }
 
-    handle.spawn(length);
+    if let Some(sync) = send_init {
+        if sessions.contains_key(&conn_id) {
+            // The user can already be seen, so broadcasts start arriving before the sync is done
+            sessions.get_mut(&conn_id).unwrap().syncing = Some(Vec::new());
+            let query = send_initial(config, sessions, sync);
+            send_reply(config, conn_id, db, done, events, pool, Reply::Query(query), sessions);
+            return;
+        }
+    }
+    let _ = done.send(sessions.contains_key(&conn_id));
+}
+/// The packets describing `user` to somebody who doesn't know about them yet.
+fn user_packets(db: &SqlConnection, online: &HashSet<usize>, user: common::User) -> Vec<Packet> {
+    let id = user.id;
+    let mut packets = vec![Packet::UserReceive(common::UserReceive {
+        inner: user
+    })];
+    if online.contains(&id) {
+        packets.push(Packet::PresenceReceive(common::PresenceReceive {
+            id: id,
+            inner: get_presence(db, true, id)
+        }));
+    }
+    packets
+}
+/// Resolves once the connection's `Writer` cuts it off, and never if it's just dropped.
+fn when_killed(killed: Shared<oneshot::Receiver<()>>) -> Box<Future<Item = (), Error = ()> + Send> {
+    Box::new(killed.then(|result| match result {
+        Ok(_) => future::Either::A(future::ok(())),
+        Err(_) => future::Either::B(future::empty())
+    }))
+}
+/// Writes everything the connection's `Writer` hands over, until it's dropped.
+fn write_packets(
+    alive: Arc<()>,
+    killed: Shared<oneshot::Receiver<()>>,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    writer: WriteHalf<SslStream<TcpStream>>
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let writes = Coalesce(receiver)
+        .fold(writer, |writer, buffer| {
+            io::write_all(writer, buffer)
+                .map(|(writer, _)| writer)
+                .map_err(|_| ())
+        })
+        .and_then(|writer| io::shutdown(writer).map_err(|_| ()));
+    Box::new(writes
+        .select2(when_killed(killed))
+        .then(move |_| {
+            // Lets shutdown know this connection is done
+            drop(alive);
+            Ok(())
+        }))
 }
 
 fn handle_packet(
+    commands: &mut PendingCommands,
     config: &Config,
     conn_id: usize,
     db: &SqlConnection,
     ip: &IpAddr,
+    logins: &mut LoginAttempts,
     packet: Packet,
+    prepared: Option<Prepared>,
     sessions: &mut HashMap<usize, Session>,
+    token_key: &[u8],
     users: &mut HashMap<usize, UserSession>
 ) -> Reply {
     macro_rules! get_id {
@@ -796,7 +2057,7 @@ fn handle_packet(
             let mut stop = false;
             {
                 let user = &mut users.entry($id).or_insert_with(UserSession::new);
-                if let Some(left) = check_rate_limits(config, $expensive, user) {
+                if let Some(left) = check_rate_limits(config, $expensive, user, $id) {
                     let session = &mut sessions.get_mut(&conn_id).unwrap();
                     write(&mut session.writer, Packet::RateLimited(left));
                     stop = true;
@@ -818,6 +2079,139 @@ fn handle_packet(
 
     match packet {
         Packet::Close => { Reply::Close }
+        Packet::BackupCreate(_) => {
+            let id = get_id!();
+            rate_limit!(id, expensive);
+
+            // Backups have everything in them, so only the owner gets to make one
+            if id != config.owner_id {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            let dir = config.backup_dir.clone();
+            // Copying the whole database takes a while, so it's left to a worker
+            Reply::Query(Box::new(move |db: &SqlConnection| {
+                match backup::snapshot_into(db, &dir) {
+                    // Where the backup directory is isn't anybody else's business
+                    Ok(path) => Reply::Reply(Packet::BackupReceive(common::BackupReceive {
+                        name: path.file_name().unwrap().to_string_lossy().into_owned()
+                    })),
+                    Err(err) => {
+                        eprintln!("Failed to back up the database: {}", err);
+                        Reply::Reply(Packet::Err(common::ERR_BACKUP_FAILED))
+                    }
+                }
+            }))
+        },
+        Packet::BotCreate(event) => {
+            let id = get_id!();
+            rate_limit!(id, expensive);
+
+            if !has_perm(
+                config,
+                id,
+                calculate_permissions_by_user(db, id, None).unwrap(),
+                common::PERM_MANAGE_BOTS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            if event.name.len() < config.limit_user_name_min
+                || event.name.len() > config.limit_user_name_max {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let count: i64 = db.query_row(
+                "SELECT COUNT(*) FROM users WHERE name = ?",
+                &[&event.name],
+                |row| row.get(0)
+            ).unwrap();
+            if count != 0 {
+                return Reply::Reply(Packet::Err(common::ERR_NAME_TAKEN));
+            }
+
+            // Bots can't log in with a password, only with their token
+            db.execute(
+                "INSERT INTO users (bot, last_ip, name, password, token) VALUES (1, '', ?, '', '')",
+                &[&event.name]
+            ).unwrap();
+            let bot = db.last_insert_rowid() as usize;
+            db.execute("INSERT INTO bots (id, owner) VALUES (?, ?)", &[&(bot as i64), &(id as i64)]).unwrap();
+
+            let (_, token) = attempt_or!(create_token(db, token_key, bot, "bot", ip), {
+                eprintln!("Failed to generate random token");
+                return Reply::Close;
+            });
+            let session = sessions.get_mut(&conn_id).unwrap();
+            write(&mut session.writer, Packet::BotTokenReceive(common::BotTokenReceive {
+                id: bot,
+                token: token
+            }));
+
+            Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
+                inner: db.get_user(bot).unwrap()
+            }))
+        },
+        Packet::BotDelete(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if !has_perm(
+                config,
+                id,
+                calculate_permissions_by_user(db, id, None).unwrap(),
+                common::PERM_MANAGE_BOTS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            let bot = match db.get_user(event.id) {
+                Some(ref user) if !user.bot => return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_BOT)),
+                Some(user) => user,
+                None => return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_BOT))
+            };
+
+            for table in &["bots", "users"] {
+                db.execute(&format!("DELETE FROM {} WHERE id = ?", table), &[&(bot.id as i64)]).unwrap();
+            }
+            db.execute("DELETE FROM commands WHERE bot = ?", &[&(bot.id as i64)]).unwrap();
+            for table in &["presence", "recovery_codes", "tokens", "totp"] {
+                db.execute(&format!("DELETE FROM {} WHERE user = ?", table), &[&(bot.id as i64)]).unwrap();
+            }
+            sessions.retain(|_, s| s.id != Some(bot.id));
+            users.remove(&bot.id);
+
+            Reply::Broadcast(None, Packet::UserDeleteReceive(common::UserDeleteReceive {
+                inner: bot
+            }))
+        },
+        Packet::BotTokenReset(event) => {
+            let id = get_id!();
+            rate_limit!(id, expensive);
+
+            if !has_perm(
+                config,
+                id,
+                calculate_permissions_by_user(db, id, None).unwrap(),
+                common::PERM_MANAGE_BOTS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            match db.get_user(event.id) {
+                Some(ref user) if user.bot => (),
+                _ => return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_BOT))
+            }
+
+            db.execute("DELETE FROM tokens WHERE user = ?", &[&(event.id as i64)]).unwrap();
+            // Kick everything using the old tokens, except for whoever sent this
+            sessions.retain(|i, s| *i == conn_id || s.id != Some(event.id));
+
+            let (_, token) = attempt_or!(create_token(db, token_key, event.id, "bot", ip), {
+                eprintln!("Failed to generate random token");
+                return Reply::Close;
+            });
+            Reply::Reply(Packet::BotTokenReceive(common::BotTokenReceive {
+                id: event.id,
+                token: token
+            }))
+        },
         Packet::ChannelCreate(channel) => {
             let id = get_id!();
             rate_limit!(id, cheap);
@@ -837,17 +2231,18 @@ fn handle_packet(
             }
 
             db.execute(
-                "INSERT INTO channels (name) VALUES (?)",
-                &[&channel.name]
+                "INSERT INTO channels (name, retention_days) VALUES (?, ?)",
+                &[&channel.name, &channel.retention_days.map(|days| days as i64)]
             ).unwrap();
             let channel_id = db.last_insert_rowid() as usize;
-            insert_channel_overrides(db, channel_id, &channel.overrides);
+            db.insert_channel_overrides(channel_id, &channel.overrides);
 
             Reply::Broadcast(None, Packet::ChannelReceive(common::ChannelReceive {
                 inner: common::Channel {
                     overrides:  channel.overrides,
                     id: channel_id,
-                    name: channel.name
+                    name: channel.name,
+                    retention_days: channel.retention_days
                 }
             }))
         },
@@ -855,7 +2250,7 @@ fn handle_packet(
             let id = get_id!();
             rate_limit!(id, cheap);
 
-            let channel = unwrap_or_err!(get_channel(db, event.id), common::ERR_UNKNOWN_CHANNEL);
+            let channel = unwrap_or_err!(db.get_channel(event.id), common::ERR_UNKNOWN_CHANNEL);
 
             if !has_perm(
                 config,
@@ -869,12 +2264,18 @@ fn handle_packet(
             db.execute("DELETE FROM messages WHERE channel = ?", &[&(event.id as i64)]).unwrap();
             db.execute("DELETE FROM overrides WHERE channel = ?", &[&(event.id as i64)]).unwrap();
             db.execute("DELETE FROM channels WHERE id = ?", &[&(event.id as i64)]).unwrap();
+            db.execute(
+                "DELETE FROM webhook_queue WHERE webhook IN (SELECT id FROM webhooks WHERE channel = ?)",
+                &[&(event.id as i64)]
+            ).unwrap();
+            db.execute("DELETE FROM webhooks WHERE channel = ?", &[&(event.id as i64)]).unwrap();
 
             Reply::Broadcast(None, Packet::ChannelDeleteReceive(common::ChannelDeleteReceive {
                 inner: common::Channel {
                     id: channel.id,
                     name: channel.name,
-                    overrides: channel.overrides
+                    overrides: channel.overrides,
+                    retention_days: channel.retention_days
                 }
             }))
         },
@@ -889,7 +2290,7 @@ fn handle_packet(
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
 
-            let old = unwrap_or_err!(get_channel(db, channel.id), common::ERR_UNKNOWN_CHANNEL);
+            let old = unwrap_or_err!(db.get_channel(channel.id), common::ERR_UNKNOWN_CHANNEL);
 
             if !has_perm(
                 config,
@@ -900,44 +2301,172 @@ fn handle_packet(
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
 
-            db.execute(
-                "UPDATE channels SET name = ? WHERE id = ?",
-                &[&channel.name, &(channel.id as i64)]
-            ).unwrap();
-            if !event.keep_overrides {
-                insert_channel_overrides(db, channel.id, &channel.overrides);
+            db.execute(
+                "UPDATE channels SET name = ?, retention_days = ? WHERE id = ?",
+                &[&channel.name, &channel.retention_days.map(|days| days as i64), &(channel.id as i64)]
+            ).unwrap();
+            if !event.keep_overrides {
+                db.insert_channel_overrides(channel.id, &channel.overrides);
+            }
+
+            let packet = Packet::ChannelReceive(common::ChannelReceive {
+                inner: common::Channel {
+                    overrides:  channel.overrides,
+                    id: channel.id,
+                    name: channel.name,
+                    retention_days: channel.retention_days
+                }
+            });
+            webhooks::queue(db, channel.id, &packet);
+            Reply::Broadcast(None, packet)
+        },
+        Packet::Command(cmd) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let length = cmd.args.iter().fold(0, |acc, item| acc + item.len()) + (cmd.args.len() - 1);
+            if length < config.limit_channel_name_min || length > config.limit_message_max {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let count: i64 = db.query_row(
+                "SELECT COUNT(*) FROM users WHERE id = ? AND bot = 1",
+                &[&(cmd.recipient as i64)],
+                |row| row.get(0)
+            ).unwrap();
+
+            if count == 0 {
+                return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_BOT));
+            }
+            let args = match parse_command(db, cmd.recipient, cmd.args) {
+                Ok(args) => args,
+                Err(err) => return Reply::Reply(Packet::Err(err))
+            };
+            // No point in waiting for a bot that isn't even there
+            if !sessions.values().any(|session| session.id == Some(cmd.recipient)) {
+                return Reply::Reply(Packet::CommandTimeoutReceive(common::CommandTimeoutReceive {
+                    id: cmd.id
+                }));
+            }
+            let registered: i64 = db.query_row(
+                "SELECT COUNT(*) FROM commands WHERE bot = ?",
+                &[&(cmd.recipient as i64)],
+                |row| row.get(0)
+            ).unwrap();
+
+            // Clients pick their own IDs, so they can't be trusted to be unique
+            let request = commands.next;
+            commands.next += 1;
+            commands.requests.insert(request, PendingCommand {
+                author: id,
+                bot: cmd.recipient,
+                conn_id: conn_id,
+                id: cmd.id,
+                notify: registered != 0,
+                sent: Instant::now()
+            });
+
+            Reply::Private(cmd.recipient, Packet::CommandReceive(common::CommandReceive {
+                args: args,
+                author: id,
+                id: request
+            }))
+        },
+        Packet::CommandRegister(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if !db.get_user(id).unwrap().bot {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            if event.commands.len() > config.limit_command_amount_max {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let valid_name = |name: &str| {
+                !name.is_empty()
+                    && name.len() <= config.limit_user_name_max
+                    && !name.contains(char::is_whitespace)
+            };
+            for (i, command) in event.commands.iter().enumerate() {
+                if !valid_name(&command.name) || command.description.len() > config.limit_message_max {
+                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                }
+                if event.commands[..i].iter().any(|other| other.name == command.name) {
+                    return Reply::Reply(Packet::Err(common::ERR_NAME_TAKEN));
+                }
+
+                let mut optional = false;
+                for (j, arg) in command.args.iter().enumerate() {
+                    if !valid_name(&arg.name) {
+                        return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                    }
+                    // Only trailing arguments can be left out, and nothing can come after the rest
+                    if (optional && !arg.optional)
+                        || (arg.kind == common::ArgKind::Rest && j + 1 != command.args.len()) {
+                        return Reply::Reply(Packet::Err(common::ERR_COMMAND_INVALID));
+                    }
+                    optional = arg.optional;
+                }
+            }
+
+            let list = common::CommandListReceive {
+                bot: id,
+                commands: event.commands
+            };
+            // The whole list is sent as one packet
+            let size = common::serialize(&Packet::CommandListReceive(list.clone()))
+                .map(|encoded| encoded.len())
+                .unwrap_or(std::usize::MAX);
+            if size > std::u16::MAX as usize {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
 
-            Reply::Broadcast(None, Packet::ChannelReceive(common::ChannelReceive {
-                inner: common::Channel {
-                    overrides:  channel.overrides,
-                    id: channel.id,
-                    name: channel.name
-                }
-            }))
+            // Nobody should ever see half a list
+            db.execute_batch("SAVEPOINT command_register").unwrap();
+            db.execute("DELETE FROM commands WHERE bot = ?", &[&(id as i64)]).unwrap();
+            for command in &list.commands {
+                db.execute(
+                    "INSERT INTO commands (args, bot, description, name) VALUES (?, ?, ?, ?)",
+                    &[
+                        &serde_json::to_string(&command.args).unwrap(),
+                        &(id as i64),
+                        &command.description,
+                        &command.name
+                    ]
+                ).unwrap();
+            }
+            db.execute_batch("RELEASE command_register").unwrap();
+
+            Reply::Broadcast(None, Packet::CommandListReceive(list))
         },
-        Packet::Command(cmd) => {
+        Packet::CommandResponse(event) => {
             let id = get_id!();
             rate_limit!(id, cheap);
 
-            let length = cmd.args.iter().fold(0, |acc, item| acc + item.len()) + (cmd.args.len() - 1);
-            if length < config.limit_channel_name_min || length > config.limit_message_max {
+            if event.text.len() < config.limit_message_min
+                || event.text.len() > config.limit_message_max {
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
+            match commands.requests.get(&event.id) {
+                Some(command) if command.bot == id => (),
+                _ => return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_COMMAND))
+            }
+            let command = commands.requests.remove(&event.id).unwrap();
 
-            let count: i64 = db.query_row(
-                "SELECT COUNT(*) FROM users WHERE id = ? AND bot = 1",
-                &[&(cmd.recipient as i64)],
-                |row| row.get(0)
-            ).unwrap();
-
-            if count == 0 {
-                return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_BOT));
+            let packet = Packet::CommandResponseReceive(common::CommandResponseReceive {
+                author: id,
+                ephemeral: event.ephemeral,
+                id: command.id,
+                text: event.text
+            });
+            if !event.ephemeral {
+                return Reply::Private(command.author, packet);
             }
-            Reply::Private(cmd.recipient, Packet::CommandReceive(common::CommandReceive {
-                args: cmd.args,
-                author: id
-            }))
+            if let Some(session) = sessions.get_mut(&command.conn_id) {
+                write(&mut session.writer, packet);
+            }
+            Reply::None
         },
         Packet::GroupCreate(group) => {
             let id = get_id!();
@@ -1003,11 +2532,18 @@ fn handle_packet(
             ) {
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
-            let group = unwrap_or_err!(get_group(db, event.id), common::ERR_UNKNOWN_GROUP);
+            let group = unwrap_or_err!(db.get_group(event.id), common::ERR_UNKNOWN_GROUP);
             if group.pos == 0 {
                 return Reply::Reply(Packet::Err(common::ERR_GROUP_INVALID_POS));
             }
 
+            // Deleting the group takes it out of user_groups, which everybody needs to hear about
+            let members: Vec<usize> = {
+                let mut stmt = db.prepare_cached("SELECT user FROM user_groups WHERE [group] = ?").unwrap();
+                let rows = stmt.query_map(&[&(group.id as i64)], |row| row.get::<_, i64>(0) as usize).unwrap();
+                rows.map(|row| row.unwrap()).collect()
+            };
+
             db.execute(
                 "DELETE FROM overrides WHERE [group] = ?",
                 &[&(group.id as i64)]
@@ -1021,7 +2557,7 @@ fn handle_packet(
                 &[&(group.id as i64)]
             ).unwrap();
 
-            Reply::Broadcast(None, Packet::GroupDeleteReceive(common::GroupDeleteReceive {
+            let mut packets = vec![Packet::GroupDeleteReceive(common::GroupDeleteReceive {
                 inner: common::Group {
                     allow: group.allow,
                     deny: group.deny,
@@ -1030,7 +2566,13 @@ fn handle_packet(
                     pos: group.pos,
                     unassignable: group.unassignable
                 }
-            }))
+            })];
+            for member in members {
+                if let Some(user) = db.get_user(member) {
+                    packets.push(Packet::UserReceive(common::UserReceive { inner: user }));
+                }
+            }
+            Reply::Broadcasts(None, packets)
         },
         Packet::GroupUpdate(event) => {
             let id = get_id!();
@@ -1050,7 +2592,7 @@ fn handle_packet(
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
 
-            let old = unwrap_or_err!(get_group(db, group.id), common::ERR_UNKNOWN_GROUP);
+            let old = unwrap_or_err!(db.get_group(group.id), common::ERR_UNKNOWN_GROUP);
             let max: i64 = db.query_row(
                 "SELECT MAX(pos) FROM groups",
                 &[],
@@ -1094,10 +2636,246 @@ fn handle_packet(
                 new: true
             }))
         },
+        Packet::IncomingWebhookCreate(event) => {
+            let id = get_id!();
+            rate_limit!(id, expensive);
+
+            let channel = unwrap_or_err!(db.get_channel(event.channel), common::ERR_UNKNOWN_CHANNEL);
+            let permissions = calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap();
+            if !has_perm(config, id, permissions, common::PERM_MANAGE_CHANNELS) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            let bot = match db.get_user(event.bot) {
+                Some(ref user) if !user.bot => return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_BOT)),
+                Some(user) => user,
+                None => return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_BOT))
+            };
+            // Otherwise anybody managing a channel could post as any bot
+            if bot.owner != Some(id)
+                && !has_perm(config, id, calculate_permissions_by_user(db, id, None).unwrap(), common::PERM_MANAGE_BOTS) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let token = attempt_or!(gen_token(), {
+                eprintln!("Failed to generate random token");
+                return Reply::Close;
+            });
+            let hash = attempt_or!(hash_token(token_key, &token), {
+                eprintln!("Failed to hash token");
+                return Reply::Close;
+            });
+            db.execute(
+                "INSERT INTO incoming_webhooks (bot, channel, token) VALUES (?, ?, ?)",
+                &[&(bot.id as i64), &(channel.id as i64), &hash]
+            ).unwrap();
+
+            Reply::Reply(Packet::IncomingWebhookReceive(common::IncomingWebhookReceive {
+                inner: common::IncomingWebhook {
+                    bot: bot.id,
+                    channel: channel.id,
+                    id: db.last_insert_rowid() as usize,
+                    token: Some(token)
+                }
+            }))
+        },
+        Packet::IncomingWebhookDelete(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let channel: Option<i64> = db.query_row(
+                "SELECT channel FROM incoming_webhooks WHERE id = ?",
+                &[&(event.id as i64)],
+                |row| row.get(0)
+            ).ok();
+            let channel = unwrap_or_err!(channel, common::ERR_UNKNOWN_WEBHOOK);
+            let channel = db.get_channel(channel as usize).unwrap();
+            if !has_perm(
+                config,
+                id,
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_MANAGE_CHANNELS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            db.execute("DELETE FROM incoming_webhooks WHERE id = ?", &[&(event.id as i64)]).unwrap();
+            Reply::None
+        },
+        Packet::IncomingWebhookList(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let channel = unwrap_or_err!(db.get_channel(event.channel), common::ERR_UNKNOWN_CHANNEL);
+            if !has_perm(
+                config,
+                id,
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_MANAGE_CHANNELS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let session = sessions.get_mut(&conn_id).unwrap();
+            let mut stmt = db.prepare_cached("SELECT bot, channel, id FROM incoming_webhooks WHERE channel = ?").unwrap();
+            let mut rows = stmt.query(&[&(channel.id as i64)]).unwrap();
+
+            while let Some(row) = rows.next() {
+                write(&mut session.writer, Packet::IncomingWebhookReceive(common::IncomingWebhookReceive {
+                    inner: get_incoming_webhook_by_fields(&row.unwrap())
+                }));
+            }
+            Reply::None
+        },
+        Packet::InviteCreate(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if event.groups.len() > config.limit_group_amount_max || event.max_uses == Some(0) {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+            let user = db.get_user(id).unwrap();
+            let perms = calculate_permissions(db, user.bot, id, None);
+            if !has_perm(config, id, perms, common::PERM_ASSIGN_GROUPS) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let mut groups = event.groups;
+            groups.sort_unstable();
+            groups.dedup();
+
+            if !groups.is_empty() {
+                if groups.iter().any(|group| *group <= RESERVED_ROLES) {
+                    return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+                }
+                let valid = if has_perm(config, id, perms, common::PERM_MANAGE_GROUPS) {
+                    groups.iter().all(|&group| db.get_group(group).is_some())
+                } else {
+                    groups_assignable(db, &groups, id)
+                };
+                if !valid {
+                    return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+                }
+            }
+
+            let code = attempt_or!(gen_random(16), {
+                eprintln!("Failed to generate random invite code");
+                return Reply::Close;
+            });
+            db.execute(
+                "INSERT INTO invites (code, creator, expires, groups, max_uses) VALUES (?, ?, ?, ?, ?)",
+                &[&code, &(id as i64), &event.expires, &from_list(&groups),
+                &event.max_uses.map(|uses| uses as i64)]
+            ).unwrap();
+
+            Reply::Reply(Packet::InviteReceive(common::InviteReceive {
+                inner: common::Invite {
+                    code: code,
+                    creator: id,
+                    expires: event.expires,
+                    groups: groups,
+                    max_uses: event.max_uses,
+                    uses: 0
+                }
+            }))
+        },
+        Packet::InviteRevoke(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let mut stmt = db.prepare_cached("SELECT * FROM invites WHERE code = ?").unwrap();
+            let mut rows = stmt.query(&[&event.code]).unwrap();
+            let invite = match rows.next() {
+                Some(row) => get_invite_by_fields(&row.unwrap()),
+                None => return Reply::Reply(Packet::Err(common::ERR_INVITE_INVALID))
+            };
+
+            if invite.creator != id && !has_perm(
+                config,
+                id,
+                calculate_permissions_by_user(db, id, None).unwrap(),
+                common::PERM_ASSIGN_GROUPS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            db.execute("DELETE FROM invites WHERE code = ?", &[&invite.code]).unwrap();
+            Reply::None
+        },
         Packet::Login(login) => {
-            let mut stmt = db.prepare_cached(
-                "SELECT id, ban, bot, token, password FROM users WHERE name = ?"
+            macro_rules! login_failed {
+                ($user:expr, $err:expr) => {
+                    record_login_failure(config, logins, *ip, $user);
+                    return Reply::Reply(Packet::Err($err));
+                }
+            }
+            if let Some(left) = check_login_backoff(config, logins.ips.get(ip), config.limit_login_failures_per_ip) {
+                return Reply::Reply(Packet::RateLimited(left));
+            }
+
+            let mut authenticated = match prepared {
+                Some(Prepared::Authenticated(result)) => Some(attempt_or!(result, {
+                    eprintln!("Failed to authenticate user");
+                    return Reply::Close;
+                })),
+                _ => None
+            };
+            // Gets the result of checking the password, which a worker does first
+            macro_rules! authenticate {
+                () => {
+                    match authenticated.take() {
+                        Some(valid) => valid,
+                        None => {
+                            let prepare = Prepare::Authenticate(
+                                config.auth.clone(),
+                                login.name.clone(),
+                                login.password.clone().unwrap()
+                            );
+                            return Reply::Prepare(Packet::Login(login), prepare);
+                        }
+                    }
+                }
+            }
+            let mut identity = None;
+            let mut provisioned = false;
+
+            let count: i64 = db.query_row(
+                "SELECT COUNT(*) FROM users WHERE name = ?",
+                &[&login.name],
+                |row| row.get(0)
             ).unwrap();
+            if count == 0 && !login.bot {
+                // Directory users never register, their account is created the first time they log in.
+                if login.password.is_some() {
+                    if let Some(valid) = authenticate!() {
+                        if valid.provision {
+                            if login.name.len() < config.limit_user_name_min
+                                || login.name.len() > config.limit_user_name_max {
+                                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                            }
+                            // Same as registering
+                            let banned: i64 = db.query_row(
+                                "SELECT COUNT(*) FROM users WHERE ban == 1 AND last_ip = ?",
+                                &[&ip.to_string()],
+                                |row| row.get(0)
+                            ).unwrap();
+                            if banned != 0 {
+                                let session = sessions.get_mut(&conn_id).unwrap();
+                                write(&mut session.writer, Packet::Err(common::ERR_LOGIN_BANNED));
+                                return Reply::Close;
+                            }
+                            db.execute(
+                                "INSERT INTO users (bot, last_ip, name, password, provisioned, token)
+                                VALUES (0, ?, ?, '', 1, '')",
+                                &[&ip.to_string(), &login.name]
+                            ).unwrap();
+                            provisioned = true;
+                        }
+                        identity = Some(valid);
+                    }
+                }
+            }
+
+            let mut stmt = db.prepare_cached("SELECT id, ban, bot, provisioned FROM users WHERE name = ?").unwrap();
             let mut rows = stmt.query(&[&login.name]).unwrap();
 
             if let Some(row) = rows.next() {
@@ -1106,8 +2884,7 @@ fn handle_packet(
                 let row_id = row.get::<_, i64>(0) as usize;
                 let row_ban: bool = row.get(1);
                 let row_bot: bool = row.get(2);
-                let row_token:    String = row.get(3);
-                let row_password: String = row.get(4);
+                let row_provisioned: bool = row.get(3);
 
                 if row_ban {
                     let session = sessions.get_mut(&conn_id).unwrap();
@@ -1117,102 +2894,120 @@ fn handle_packet(
                 if row_bot != login.bot {
                     return Reply::Reply(Packet::Err(common::ERR_LOGIN_BOT));
                 }
-                if let Some(password) = login.password {
-                    let valid = attempt_or!(bcrypt::verify(&password, &row_password), {
-                        eprintln!("Failed to verify password");
+                if login.password.is_some() {
+                    // Only passwords can be guessed, so devices that already have a token aren't locked out
+                    if let Some(left) = check_login_backoff(
+                        config,
+                        logins.users.get(&row_id),
+                        config.limit_login_failures_per_account
+                    ) {
+                        return Reply::Reply(Packet::RateLimited(left));
+                    }
+                    let identity = match identity {
+                        Some(identity) => identity,
+                        None => match authenticate!() {
+                            Some(identity) => identity,
+                            None => { login_failed!(Some(row_id), common::ERR_LOGIN_INVALID); }
+                        }
+                    };
+                    // A directory user can't take over a local account that happens to have the same name
+                    if identity.provision && !row_provisioned {
+                        login_failed!(Some(row_id), common::ERR_LOGIN_INVALID);
+                    }
+                    let totp: i64 = db.query_row(
+                        "SELECT COUNT(*) FROM totp WHERE user = ? AND confirmed = 1",
+                        &[&(row_id as i64)],
+                        |row| row.get(0)
+                    ).unwrap();
+                    if totp != 0 {
+                        match login.totp {
+                            None => return Reply::Reply(Packet::LoginChallenge(common::LoginChallenge)),
+                            Some(ref code) => if !verify_totp(db, token_key, row_id, code) {
+                                login_failed!(Some(row_id), common::ERR_TOTP_INVALID);
+                            }
+                        }
+                    }
+                    let device = login.device.unwrap_or_else(|| String::from("unnamed device"));
+                    if device.len() > config.limit_user_name_max {
+                        return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                    }
+                    let (token_id, token) = attempt_or!(create_token(db, token_key, row_id, &device, ip), {
+                        eprintln!("Failed to generate random token");
                         return Reply::Close;
                     });
-                    if !valid {
-                        return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
-                    }
                     db.execute(
                         "UPDATE users SET last_ip = ? WHERE id = ?",
                         &[&ip.to_string(), &(row_id as i64)]
                     ).unwrap();
-                    sessions
-                        .get_mut(&conn_id).unwrap()
-                        .id = Some(row_id);
-                    Reply::SendInitial(Box::new(Reply::Reply(Packet::LoginSuccess(common::LoginSuccess {
-                        created: false,
-                        id: row_id,
-                        token: row_token
-                    }))))
+                    logins.ips.remove(ip);
+                    logins.users.remove(&row_id);
+                    if sync_groups(db, row_id, &identity) || provisioned {
+                        write_broadcast(
+                            None,
+                            config,
+                            db,
+                            &Packet::UserReceive(common::UserReceive {
+                                inner: db.get_user(row_id).unwrap()
+                            }),
+                            None,
+                            sessions
+                        );
+                    }
+                    let online = is_online(sessions, row_id);
+                    {
+                        let session = sessions.get_mut(&conn_id).unwrap();
+                        session.id = Some(row_id);
+                        session.token = Some(token_id);
+                    }
+                    finish_login(config, conn_id, db, login.events, online, login.resume, sessions, login.sync, token, row_id)
                 } else if let Some(token) = login.token {
-                    if token != row_token {
-                        return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
+                    let hash = attempt_or!(hash_token(token_key, &token), {
+                        eprintln!("Failed to hash token");
+                        return Reply::Close;
+                    });
+                    let mut token_id = None;
+                    {
+                        let mut stmt = db.prepare_cached("SELECT id, token FROM tokens WHERE user = ?").unwrap();
+                        let mut rows = stmt.query(&[&(row_id as i64)]).unwrap();
+
+                        while let Some(row) = rows.next() {
+                            let row = row.unwrap();
+                            if verify_token(&hash, &row.get::<_, String>(1), &token) {
+                                token_id = Some(row.get::<_, i64>(0));
+                            }
+                        }
                     }
+                    let token_id = match token_id {
+                        Some(some) => some,
+                        // Stale tokens are common, so only the IP is held accountable
+                        None => { login_failed!(None, common::ERR_LOGIN_INVALID); }
+                    };
+                    db.execute(
+                        "UPDATE tokens SET last_ip = ?, last_used = ?, token = ? WHERE id = ?",
+                        &[&ip.to_string(), &Utc::now().timestamp(), &hash, &token_id]
+                    ).unwrap();
                     db.execute(
                         "UPDATE users SET last_ip = ? WHERE id = ?",
                         &[&ip.to_string(), &(row_id as i64)]
                     ).unwrap();
-                    sessions
-                        .get_mut(&conn_id).unwrap()
-                        .id = Some(row_id);
-                    Reply::SendInitial(Box::new(Reply::Reply(Packet::LoginSuccess(common::LoginSuccess {
-                        created: false,
-                        id: row_id,
-                        token: token
-                    }))))
+                    // Failed passwords for the account still count, or reconnecting devices would reset them
+                    logins.ips.remove(ip);
+                    let online = is_online(sessions, row_id);
+                    {
+                        let session = sessions.get_mut(&conn_id).unwrap();
+                        session.id = Some(row_id);
+                        session.token = Some(token_id as usize);
+                    }
+                    finish_login(config, conn_id, db, login.events, online, login.resume, sessions, login.sync, token, row_id)
                 } else {
                     Reply::Reply(Packet::Err(common::ERR_MISSING_FIELD))
                 }
-            } else if let Some(password) = login.password {
-                if login.name.len() < config.limit_user_name_min
-                    || login.name.len() > config.limit_user_name_max {
-                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
-                }
-
-                let count: i64 = db.query_row(
-                    "SELECT COUNT(*) FROM users WHERE ban == 1 AND last_ip = ?",
-                    &[&ip.to_string()],
-                    |row| row.get(0)
-                ).unwrap();
-
-                if count != 0 {
-                    let session = sessions.get_mut(&conn_id).unwrap();
-                    write(&mut session.writer, Packet::Err(common::ERR_LOGIN_BANNED));
-                    return Reply::Close;
-                }
-
-                let password = attempt_or!(bcrypt::hash(&password, bcrypt::DEFAULT_COST), {
-                    eprintln!("Failed to hash password");
-                    return Reply::Close;
-                });
-                let token = attempt_or!(gen_token(), {
-                    eprintln!("Failed to generate random token");
-                    return Reply::Close;
-                });
-
-                db.execute(
-                    "INSERT INTO users (bot, last_ip, name, password, token) VALUES (?, ?, ?, ?, ?)",
-                    &[&login.bot, &ip.to_string(), &login.name, &password, &token]
-                ).unwrap();
-
-                let id = db.last_insert_rowid() as usize;
-                let session = sessions.get_mut(&conn_id).unwrap();
-                session.id = Some(id);
-
-                write(&mut session.writer, Packet::LoginSuccess(common::LoginSuccess {
-                    created: true,
-                    id: id,
-                    token: token
-                }));
-
-                Reply::SendInitial(Box::new(Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
-                    inner: common::User {
-                        ban: false,
-                        bot: login.bot,
-                        groups: Vec::new(),
-                        id: id,
-                        name: login.name
-                    }
-                }))))
             } else {
-                return Reply::Reply(Packet::Err(common::ERR_MISSING_FIELD));
+                login_failed!(None, common::ERR_UNKNOWN_USER);
             }
         },
         Packet::LoginUpdate(login) => {
-            let mut reset_token = login.reset_token;
+            let reset_token = login.reset_token;
             let id = get_id!();
             rate_limit!(id, reset_token
                         || (login.password_current.is_some()
@@ -1229,87 +3024,74 @@ fn handle_packet(
                 }
                 db.execute("UPDATE users SET name = ? WHERE id = ?", &[&name, &(id as i64)]).unwrap();
             }
-            if let Some(current) = login.password_current {
-                let new = unwrap_or_err!(login.password_new, common::ERR_MISSING_FIELD);
-
-                let mut stmt = db.prepare_cached("SELECT password FROM users WHERE id = ?").unwrap();
-                let mut rows = stmt.query(&[&(id as i64)]).unwrap();
-                let password: String = rows.next().unwrap().unwrap().get(0);
-
-                let valid = attempt_or!(bcrypt::verify(&current, &password), {
-                    eprintln!("Failed to verify password");
-                    return Reply::Close;
-                });
-                if !valid {
-                    return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
-                }
-
-                let hash = attempt_or!(bcrypt::hash(&new, bcrypt::DEFAULT_COST), {
-                    eprintln!("Failed to hash password");
-                    return Reply::Close;
-                });
-                db.execute("UPDATE users SET password = ? WHERE id = ?", &[&hash, &(id as i64)])
-                    .unwrap();
-                reset_token = true;
-            }
-            if reset_token {
-                let token = attempt_or!(gen_token(), {
-                    eprintln!("Failed to generate random token");
-                    return Reply::Close;
-                });
-                db.execute("UPDATE users SET token = ? WHERE id = ?", &[&token, &(id as i64)]).unwrap();
-                return Reply::Reply(Packet::LoginSuccess(common::LoginSuccess {
-                    created: false,
-                    id: id,
-                    token: token
-                }));
-            }
-            Reply::None
-        },
-        Packet::MessageCreate(msg) => {
-            let id = get_id!();
-            rate_limit!(id, cheap);
-
-            if msg.text.len() < config.limit_message_min
-                || msg.text.len() > config.limit_message_max {
-                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            let password = match login.password_current {
+                Some(current) => Some((current, unwrap_or_err!(login.password_new, common::ERR_MISSING_FIELD))),
+                None => None
+            };
+            if password.is_none() && !reset_token {
+                return Reply::None;
             }
 
-            let channel = unwrap_or_err!(get_channel(db, msg.channel), common::ERR_UNKNOWN_CHANNEL);
-            let timestamp = Utc::now().timestamp();
-
-            if !has_perm(
-                config,
-                id,
-                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
-                common::PERM_WRITE
-            ) {
-                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
-            }
+            let auth = config.auth.clone();
+            let token_id = sessions[&conn_id].token.unwrap();
+            let token_key = token_key.to_vec();
+            // Checking and hashing passwords takes a while
+            Reply::Query(Box::new(move |db: &SqlConnection| {
+                if let Some((current, new)) = password {
+                    let name = db.get_user(id).unwrap().name;
+                    let provider = auth.provider_for(db, &name);
+
+                    let valid = attempt_or!(provider.authenticate(db, &name, &current), {
+                        eprintln!("Failed to authenticate user");
+                        return Reply::Close;
+                    });
+                    if valid.is_none() {
+                        return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
+                    }
 
-            db.execute(
-                "INSERT INTO messages (author, channel, text, timestamp) VALUES (?, ?, ?, ?)",
-                &[&(id as i64), &(msg.channel as i64), &msg.text, &timestamp]
-            ).unwrap();
+                    match provider.change_password(db, id, &new) {
+                        Ok(()) => (),
+                        Err(auth::AuthError::Unsupported) =>
+                            return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION)),
+                        Err(_) => {
+                            eprintln!("Failed to change password");
+                            return Reply::Close;
+                        }
+                    }
+                }
 
-            Reply::Broadcast(Some(channel.overrides), Packet::MessageReceive(common::MessageReceive {
-                inner: common::Message {
-                    author: id,
-                    channel: msg.channel,
-                    id: db.last_insert_rowid() as usize,
-                    text: msg.text,
-                    timestamp: timestamp,
-                    timestamp_edit: None
-                },
-                new: true
+                let token = attempt_or!(gen_token(), {
+                    eprintln!("Failed to generate random token");
+                    return Reply::Close;
+                });
+                let hash = attempt_or!(hash_token(&token_key, &token), {
+                    eprintln!("Failed to hash token");
+                    return Reply::Close;
+                });
+                db.execute("UPDATE tokens SET token = ? WHERE id = ?", &[&hash, &(token_id as i64)]).unwrap();
+                Reply::Reply(Packet::LoginSuccess(common::LoginSuccess {
+                    id: id,
+                    resumed: false,
+                    seq: journal_seq(db),
+                    token: token
+                }))
             }))
         },
+        Packet::MessageCreate(msg) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            match create_message(config, db, id, msg.channel, msg.text) {
+                Ok((channel, packet)) => Reply::Broadcast(Some(channel.overrides), packet),
+                Err(err) => Reply::Reply(Packet::Err(err))
+            }
+        },
         Packet::MessageDelete(event) => {
             let id = get_id!();
             rate_limit!(id, cheap);
 
-            let msg = unwrap_or_err!(get_message(db, event.id), common::ERR_UNKNOWN_CHANNEL);
-            let channel = get_channel(db, msg.channel).unwrap();
+            let msg = unwrap_or_err!(db.get_message(event.id), common::ERR_UNKNOWN_CHANNEL);
+            let channel = db.get_channel(msg.channel).unwrap();
 
             if msg.author != id && !has_perm(
                 config,
@@ -1325,9 +3107,11 @@ fn handle_packet(
                 &[&(event.id as i64)]
             ).unwrap();
 
-            Reply::Broadcast(Some(channel.overrides), Packet::MessageDeleteReceive(common::MessageDeleteReceive {
+            let packet = Packet::MessageDeleteReceive(common::MessageDeleteReceive {
                 id: event.id
-            }))
+            });
+            webhooks::queue(db, channel.id, &packet);
+            Reply::Broadcast(Some(channel.overrides), packet)
         },
         Packet::MessageDeleteBulk(event) => {
             if event.ids.is_empty() || event.ids.len() > common::LIMIT_BULK {
@@ -1337,7 +3121,7 @@ fn handle_packet(
             let id = get_id!();
             rate_limit!(id, event.ids.len() != 1);
 
-            let channel = unwrap_or_err!(get_channel(db, event.channel), common::ERR_UNKNOWN_CHANNEL);
+            let channel = unwrap_or_err!(db.get_channel(event.channel), common::ERR_UNKNOWN_CHANNEL);
 
             let has = has_perm(
                 config,
@@ -1346,55 +3130,53 @@ fn handle_packet(
                 common::PERM_MANAGE_MESSAGES
             );
 
-            let list = from_list(&event.ids);
-            let correct = {
-                let mut query = String::with_capacity(43 + 1 + 39);
-                query.push_str("SELECT COUNT(*) FROM messages WHERE id IN (");
-                query.push_str(&list);
-                query.push_str(") AND channel = ? AND (? OR author = ?)");
-
-                let count: i64 = db.query_row(
-                    &query,
-                    &[&(event.channel as i64), &has, &(id as i64)],
-                    |row| row.get(0)
-                ).unwrap();
+            // Deleting a lot of messages can take a while
+            Reply::Query(Box::new(move |db: &SqlConnection| {
+                let list = from_list(&event.ids);
+                let correct = {
+                    let mut query = String::with_capacity(43 + 1 + 39);
+                    query.push_str("SELECT COUNT(*) FROM messages WHERE id IN (");
+                    query.push_str(&list);
+                    query.push_str(") AND channel = ? AND (? OR author = ?)");
+
+                    let count: i64 = db.query_row(
+                        &query,
+                        &[&(event.channel as i64), &has, &(id as i64)],
+                        |row| row.get(0)
+                    ).unwrap();
 
-                count as usize == event.ids.len()
-            };
+                    count as usize == event.ids.len()
+                };
 
-            if !correct {
-                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
-                // NOTE: "MISSING PERMISSION" even if it's just the wrong channel
-                // or the message doesn't exist.
-                // TODO Replace with a more generic error? Leave as is?
-            }
-            let mut query = String::with_capacity(34 + 1 + 1);
-            query.push_str("DELETE FROM messages WHERE id IN (");
-            query.push_str(&list);
-            query.push(')');
+                if !correct {
+                    return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+                    // NOTE: "MISSING PERMISSION" even if it's just the wrong channel
+                    // or the message doesn't exist.
+                    // TODO Replace with a more generic error? Leave as is?
+                }
+                let mut query = String::with_capacity(34 + 1 + 1);
+                query.push_str("DELETE FROM messages WHERE id IN (");
+                query.push_str(&list);
+                query.push(')');
 
-            db.execute(&query, &[]).unwrap();
+                db.execute(&query, &[]).unwrap();
 
-            for msg in event.ids {
-                let packet = Packet::MessageDeleteReceive(common::MessageDeleteReceive {
-                    id: msg
-                });
-                write_broadcast(
-                    Some(&channel.overrides),
-                    config,
-                    db,
-                    &packet,
-                    None,
-                    sessions
-                );
-            }
-            Reply::None
+                let mut packets = Vec::with_capacity(event.ids.len());
+                for msg in event.ids {
+                    let packet = Packet::MessageDeleteReceive(common::MessageDeleteReceive {
+                        id: msg
+                    });
+                    webhooks::queue(db, channel.id, &packet);
+                    packets.push(packet);
+                }
+                Reply::Broadcasts(Some(channel.overrides), packets)
+            }))
         },
         Packet::MessageList(params) => {
             let id = get_id!();
             rate_limit!(id, cheap);
 
-            let channel = unwrap_or_err!(get_channel(db, params.channel), common::ERR_UNKNOWN_CHANNEL);
+            let channel = unwrap_or_err!(db.get_channel(params.channel), common::ERR_UNKNOWN_CHANNEL);
             if params.limit == 0 || params.limit > common::LIMIT_BULK {
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
@@ -1406,57 +3188,59 @@ fn handle_packet(
             ) {
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
-            let mut stmt;
-            let mut rows;
-
-            if let Some(after) = params.after {
-                stmt = db.prepare_cached(
-                    "SELECT * FROM messages
-                    WHERE channel = ? AND timestamp >=
-                    (SELECT timestamp FROM messages WHERE id = ?)
-                    ORDER BY timestamp
-                    LIMIT ?"
-                ).unwrap();
-                rows = stmt.query(&[
-                    &(params.channel as i64),
-                    &(after as i64),
-                    &(params.limit as i64)
-                ]).unwrap();
-            } else if let Some(before) = params.before {
-                stmt = db.prepare_cached(
-                    "SELECT * FROM messages
-                    WHERE channel = ? AND timestamp <=
-                    (SELECT timestamp FROM messages WHERE id = ?)
-                    ORDER BY timestamp
-                    LIMIT ?"
-                ).unwrap();
-                rows = stmt.query(&[
-                    &(params.channel as i64),
-                    &(before as i64),
-                    &(params.limit as i64)
-                ]).unwrap();
-            } else {
-                stmt = db.prepare_cached(
-                    "SELECT * FROM
-                    (SELECT * FROM messages WHERE channel = ? ORDER BY timestamp DESC LIMIT ?)
-                    ORDER BY timestamp"
-                ).unwrap();
-                rows = stmt.query(&[
-                    &(params.channel as i64),
-                    &(params.limit as i64)
-                ]).unwrap();
-            };
-
-            let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
-
-            while let Some(row) = rows.next() {
-                let msg = get_message_by_fields(&row.unwrap());
-                write(writer, Packet::MessageReceive(common::MessageReceive {
-                    inner: msg,
-                    new: false
-                }));
-            }
-            Reply::None
+            // History can be slow to look up, so it's left to a worker
+            Reply::Query(Box::new(move |db: &SqlConnection| {
+                let mut stmt;
+                let mut rows;
+
+                if let Some(after) = params.after {
+                    stmt = db.prepare_cached(
+                        "SELECT * FROM messages
+                        WHERE channel = ? AND timestamp >=
+                        (SELECT timestamp FROM messages WHERE id = ?)
+                        ORDER BY timestamp
+                        LIMIT ?"
+                    ).unwrap();
+                    rows = stmt.query(&[
+                        &(params.channel as i64),
+                        &(after as i64),
+                        &(params.limit as i64)
+                    ]).unwrap();
+                } else if let Some(before) = params.before {
+                    stmt = db.prepare_cached(
+                        "SELECT * FROM messages
+                        WHERE channel = ? AND timestamp <=
+                        (SELECT timestamp FROM messages WHERE id = ?)
+                        ORDER BY timestamp
+                        LIMIT ?"
+                    ).unwrap();
+                    rows = stmt.query(&[
+                        &(params.channel as i64),
+                        &(before as i64),
+                        &(params.limit as i64)
+                    ]).unwrap();
+                } else {
+                    stmt = db.prepare_cached(
+                        "SELECT * FROM
+                        (SELECT * FROM messages WHERE channel = ? ORDER BY timestamp DESC LIMIT ?)
+                        ORDER BY timestamp"
+                    ).unwrap();
+                    rows = stmt.query(&[
+                        &(params.channel as i64),
+                        &(params.limit as i64)
+                    ]).unwrap();
+                };
+
+                let mut packets = Vec::new();
+                while let Some(row) = rows.next() {
+                    let msg = get_message_by_fields(&row.unwrap());
+                    packets.push(Packet::MessageReceive(common::MessageReceive {
+                        inner: msg,
+                        new: false
+                    }));
+                }
+                Reply::Replies(packets)
+            }))
         },
         Packet::MessageUpdate(event) => {
             let id = get_id!();
@@ -1466,20 +3250,20 @@ fn handle_packet(
                 || event.text.len() > config.limit_message_max {
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
-            let msg = unwrap_or_err!(get_message(db, event.id), common::ERR_UNKNOWN_MESSAGE);
+            let msg = unwrap_or_err!(db.get_message(event.id), common::ERR_UNKNOWN_MESSAGE);
             let timestamp = Utc::now().timestamp();
 
             if msg.author != id {
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
-            let channel = get_channel(db, msg.channel).unwrap();
+            let channel = db.get_channel(msg.channel).unwrap();
 
             db.execute(
                 "UPDATE messages SET text = ? WHERE id = ?",
                 &[&event.text, &(event.id as i64)]
             ).unwrap();
 
-            Reply::Broadcast(Some(channel.overrides), Packet::MessageReceive(common::MessageReceive {
+            let packet = Packet::MessageReceive(common::MessageReceive {
                 inner: common::Message {
                     author: id,
                     channel: msg.channel,
@@ -1489,6 +3273,40 @@ fn handle_packet(
                     timestamp_edit: Some(timestamp)
                 },
                 new: true
+            });
+            webhooks::queue(db, channel.id, &packet);
+            Reply::Broadcast(Some(channel.overrides), packet)
+        },
+        Packet::PresenceUpdate(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let presence = event.inner;
+            let status = match presence.status {
+                common::Status::Away => "away",
+                common::Status::Dnd => "dnd",
+                common::Status::Online => "online",
+                common::Status::Offline => return Reply::Reply(Packet::Err(common::ERR_STATUS_INVALID))
+            };
+            let custom = presence.custom.and_then(|custom| {
+                let custom = custom.trim().to_string();
+                if custom.is_empty() { None } else { Some(custom) }
+            });
+            if custom.as_ref().map(|custom| custom.len() > common::LIMIT_STATUS).unwrap_or(false) {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            db.execute(
+                "REPLACE INTO presence (custom, status, user) VALUES (?, ?, ?)",
+                &[&custom, &status, &(id as i64)]
+            ).unwrap();
+
+            Reply::Broadcast(None, Packet::PresenceReceive(common::PresenceReceive {
+                id: id,
+                inner: common::Presence {
+                    custom: custom,
+                    status: presence.status
+                }
             }))
         },
         Packet::PrivateMessage(msg) => {
@@ -1514,9 +3332,252 @@ fn handle_packet(
                 text: msg.text
             }))
         },
+        Packet::Register(register) => {
+            // Bots are created by their owners with BotCreate
+            if register.bot {
+                return Reply::Reply(Packet::Err(common::ERR_LOGIN_BOT));
+            }
+            if register.name.len() < config.limit_user_name_min
+                || register.name.len() > config.limit_user_name_max {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+            if register.password.is_empty() {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_FIELD));
+            }
+
+            let count: i64 = db.query_row(
+                "SELECT COUNT(*) FROM users WHERE name = ?",
+                &[&register.name],
+                |row| row.get(0)
+            ).unwrap();
+            if count != 0 {
+                return Reply::Reply(Packet::Err(common::ERR_NAME_TAKEN));
+            }
+
+            let count: i64 = db.query_row(
+                "SELECT COUNT(*) FROM users WHERE ban == 1 AND last_ip = ?",
+                &[&ip.to_string()],
+                |row| row.get(0)
+            ).unwrap();
+
+            if count != 0 {
+                let session = sessions.get_mut(&conn_id).unwrap();
+                write(&mut session.writer, Packet::Err(common::ERR_LOGIN_BANNED));
+                return Reply::Close;
+            }
+
+            // The very first account is always allowed, or nobody could ever invite anyone.
+            let users: i64 = db.query_row("SELECT COUNT(*) FROM users", &[], |row| row.get(0)).unwrap();
+            let first = users == 0;
+
+            if config.registration == Registration::Closed && !first {
+                return Reply::Reply(Packet::Err(common::ERR_REGISTRATION_CLOSED));
+            }
+            if config.registration == Registration::InviteOnly && !first && register.invite.is_none() {
+                return Reply::Reply(Packet::Err(common::ERR_INVITE_INVALID));
+            }
+
+            let device = register.device.clone().unwrap_or_else(|| String::from("unnamed device"));
+            if device.len() > config.limit_user_name_max {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            // Hashing takes a while, so a worker does it before anything is changed
+            let password = match prepared {
+                Some(Prepared::Hashed(result)) => attempt_or!(result, {
+                    eprintln!("Failed to hash password");
+                    return Reply::Close;
+                }),
+                _ => {
+                    let prepare = Prepare::Hash(register.password.clone());
+                    return Reply::Prepare(Packet::Register(register), prepare);
+                }
+            };
+
+            let groups = match register.invite {
+                Some(ref code) => unwrap_or_err!(redeem_invite(db, code), common::ERR_INVITE_INVALID),
+                None => Vec::new()
+            };
+
+            db.execute(
+                "INSERT INTO users (bot, last_ip, name, password, token) VALUES (0, ?, ?, ?, '')",
+                &[&ip.to_string(), &register.name, &password]
+            ).unwrap();
+
+            let id = db.last_insert_rowid() as usize;
+            // The invite might hand out groups that were deleted since
+            db.set_user_groups(id, &groups);
+            let groups = db.get_user_groups(id);
+            let (token_id, token) = attempt_or!(create_token(db, token_key, id, &device, ip), {
+                eprintln!("Failed to generate random token");
+                return Reply::Close;
+            });
+            {
+                let session = sessions.get_mut(&conn_id).unwrap();
+                session.events = register.events;
+                session.id = Some(id);
+                session.token = Some(token_id);
+
+                write(&mut session.writer, Packet::LoginSuccess(common::LoginSuccess {
+                    id: id,
+                    resumed: false,
+                    seq: journal_seq(db),
+                    token: token
+                }));
+            }
+            // Nobody can make sense of a presence before they know the user
+            let packet = Packet::UserReceive(common::UserReceive {
+                inner: common::User {
+                    ban: false,
+                    bot: false,
+                    groups: groups,
+                    id: id,
+                    name: register.name,
+                    owner: None
+                }
+            });
+            write_broadcast(None, config, db, &packet, None, sessions);
+            broadcast_presence(config, db, sessions, id);
+
+            Reply::SendInitial(register.sync, Box::new(Reply::None))
+        },
+        Packet::TokenList(_) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let session = sessions.get_mut(&conn_id).unwrap();
+            let current = session.token;
+            let mut stmt = db.prepare_cached("SELECT * FROM tokens WHERE user = ?").unwrap();
+            let mut rows = stmt.query(&[&(id as i64)]).unwrap();
+
+            while let Some(row) = rows.next() {
+                write(&mut session.writer, Packet::TokenReceive(common::TokenReceive {
+                    inner: get_token_by_fields(&row.unwrap(), current)
+                }));
+            }
+            Reply::None
+        },
+        Packet::TokenRevoke(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let changed = db.execute(
+                "DELETE FROM tokens WHERE id = ? AND user = ?",
+                &[&(event.id as i64), &(id as i64)]
+            ).unwrap();
+            if changed == 0 {
+                return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_TOKEN));
+            }
+
+            sessions.retain(|_, s| s.token != Some(event.id));
+            if !is_online(sessions, id) {
+                broadcast_presence(config, db, sessions, id);
+            }
+            Reply::None
+        },
+        Packet::TotpConfirm(event) => {
+            let id = get_id!();
+            rate_limit!(id, expensive);
+
+            let secret: Vec<u8> = attempt_or!(db.query_row(
+                "SELECT secret FROM totp WHERE user = ? AND confirmed = 0",
+                &[&(id as i64)],
+                |row| row.get(0)
+            ), {
+                return Reply::Reply(Packet::Err(common::ERR_TOTP_INVALID));
+            });
+            let step = attempt_or!(totp::verify(&secret, &event.code, Utc::now().timestamp()), {
+                eprintln!("Failed to verify TOTP code");
+                return Reply::Close;
+            });
+            let step = unwrap_or_err!(step, common::ERR_TOTP_INVALID);
+
+            db.execute(
+                "UPDATE totp SET confirmed = 1, last_step = ? WHERE user = ?",
+                &[&step, &(id as i64)]
+            ).unwrap();
+            db.execute("DELETE FROM recovery_codes WHERE user = ?", &[&(id as i64)]).unwrap();
+
+            let mut codes = Vec::with_capacity(TOTP_RECOVERY_CODES);
+            for _ in 0..TOTP_RECOVERY_CODES {
+                let code = attempt_or!(gen_random(TOTP_RECOVERY_CODE_LEN), {
+                    eprintln!("Failed to generate recovery code");
+                    return Reply::Close;
+                });
+                let hash = attempt_or!(hash_token(token_key, &code), {
+                    eprintln!("Failed to hash recovery code");
+                    return Reply::Close;
+                });
+                db.execute(
+                    "INSERT INTO recovery_codes (code, user) VALUES (?, ?)",
+                    &[&hash, &(id as i64)]
+                ).unwrap();
+                codes.push(code);
+            }
+
+            Reply::Reply(Packet::TotpRecoveryReceive(common::TotpRecoveryReceive {
+                codes: codes
+            }))
+        },
+        Packet::TotpDisable(event) => {
+            let id = get_id!();
+            rate_limit!(id, expensive);
+
+            if !verify_totp(db, token_key, id, &event.code) {
+                return Reply::Reply(Packet::Err(common::ERR_TOTP_INVALID));
+            }
+
+            db.execute("DELETE FROM totp WHERE user = ?", &[&(id as i64)]).unwrap();
+            db.execute("DELETE FROM recovery_codes WHERE user = ?", &[&(id as i64)]).unwrap();
+            Reply::None
+        },
+        Packet::TotpEnroll(event) => {
+            let id = get_id!();
+
+            // Checking the password can mean asking a directory server, so a worker does it first
+            let valid = match prepared {
+                Some(Prepared::Authenticated(result)) => attempt_or!(result, {
+                    eprintln!("Failed to authenticate user");
+                    return Reply::Close;
+                }),
+                _ => {
+                    rate_limit!(id, expensive);
+
+                    let name = db.get_user(id).unwrap().name;
+                    let prepare = Prepare::Authenticate(config.auth.clone(), name, event.password.clone());
+                    return Reply::Prepare(Packet::TotpEnroll(event), prepare);
+                }
+            };
+            if valid.is_none() {
+                return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
+            }
+
+            let enabled: i64 = db.query_row(
+                "SELECT COUNT(*) FROM totp WHERE user = ? AND confirmed = 1",
+                &[&(id as i64)],
+                |row| row.get(0)
+            ).unwrap();
+            if enabled != 0 {
+                return Reply::Reply(Packet::Err(common::ERR_TOTP_ENABLED));
+            }
+
+            let mut secret = vec![0; totp::SECRET_LEN];
+            attempt_or!(rand::rand_bytes(&mut secret), {
+                eprintln!("Failed to generate TOTP secret");
+                return Reply::Close;
+            });
+            db.execute(
+                "REPLACE INTO totp (confirmed, last_step, secret, user) VALUES (0, 0, ?, ?)",
+                &[&secret, &(id as i64)]
+            ).unwrap();
+
+            Reply::Reply(Packet::TotpSecretReceive(common::TotpSecretReceive {
+                secret: totp::base32(&secret)
+            }))
+        },
         Packet::Typing(event) => {
             let id = get_id!();
-            let channel = unwrap_or_err!(get_channel(db, event.channel), common::ERR_UNKNOWN_CHANNEL);
+            let channel = unwrap_or_err!(db.get_channel(event.channel), common::ERR_UNKNOWN_CHANNEL);
             if !has_perm(
                 config,
                 id,
@@ -1531,19 +3592,37 @@ fn handle_packet(
                 channel: event.channel
             }))
         },
+        Packet::UserFetch(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if event.ids.len() > common::LIMIT_BULK {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+            let online: HashSet<usize> = sessions.values().filter_map(|s| s.id).collect();
+
+            // Users that don't exist are skipped, so a batch isn't ruined by one deleted author
+            let mut packets = Vec::new();
+            for user in event.ids {
+                if let Some(user) = db.get_user(user) {
+                    packets.extend(user_packets(db, &online, user));
+                }
+            }
+            Reply::Replies(packets)
+        },
         Packet::UserUpdate(event) => {
             let id = get_id!();
             rate_limit!(id, cheap);
-            let user = get_user(db, id).unwrap();
+            let user = db.get_user(id).unwrap();
 
-            let old = unwrap_or_err!(get_user(db, event.id), common::ERR_UNKNOWN_USER);
+            let old = unwrap_or_err!(db.get_user(event.id), common::ERR_UNKNOWN_USER);
             if let Some(ban) = event.ban {
                 if event.id == id
                     || event.id == config.owner_id
                     || !has_perm(
                     config,
                     id,
-                    calculate_permissions(db, user.bot, &user.groups, None),
+                    calculate_permissions(db, user.bot, id, None),
                     common::PERM_BAN
                 ) {
                     return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
@@ -1554,6 +3633,7 @@ fn handle_packet(
                     &[&ban, &(event.id as i64)]
                 ).unwrap();
                 sessions.retain(|_, s| s.id != Some(event.id));
+                broadcast_presence(config, db, sessions, event.id);
 
                 Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
                     inner: common::User {
@@ -1561,14 +3641,15 @@ fn handle_packet(
                         bot:  old.bot,
                         groups: old.groups,
                         id:   old.id,
-                        name: old.name
+                        name: old.name,
+                        owner: old.owner
                     }
                 }))
             } else if let Some(mut groups) = event.groups {
                 if !has_perm(
                     config,
                     id,
-                    calculate_permissions(db, user.bot, &user.groups, None),
+                    calculate_permissions(db, user.bot, id, None),
                     common::PERM_ASSIGN_GROUPS
                 ) {
                     return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION))
@@ -1592,41 +3673,19 @@ fn handle_packet(
                 let correct = if has_perm(
                     config,
                     id,
-                    calculate_permissions(db, user.bot, &user.groups, None),
+                    calculate_permissions(db, user.bot, id, None),
                     common::PERM_MANAGE_GROUPS
                 ) {
-                    let mut ok = true;
-                    for group in changed {
-                        if group <= RESERVED_ROLES {
-                            ok = false;
-                            break;
-                        }
-                    }
-                    ok
+                    changed.iter().all(|&group| group > RESERVED_ROLES && db.get_group(group).is_some())
                 } else if !changed.is_empty() {
-                    let mut query = String::with_capacity(45+1+35);
-
-                    query.push_str("SELECT COUNT(*) FROM groups WHERE id IN (");
-                    query.push_str(&from_list(&changed));
-                    query.push_str(") AND unassignable = 0 AND pos != 0");
-                    if !old.groups.is_empty() {
-                        query.push_str(" AND pos < (SELECT MAX(pos) FROM groups WHERE id IN (");
-                        query.push_str(&from_list(&old.groups));
-                        query.push_str("))");
-                    }
-
-                    let count: i64 = db.query_row(&query, &[], |row| row.get(0)).unwrap();
-                    changed.len() == count as usize
+                    groups_assignable(db, &changed, event.id)
                 } else { false };
 
                 if !correct {
                     return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
                 }
 
-                db.execute(
-                    "UPDATE users SET groups = ? WHERE id = ?",
-                    &[&from_list(&groups), &(event.id as i64)]
-                ).unwrap();
+                db.set_user_groups(event.id, &groups);
 
                 Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
                     inner: common::User {
@@ -1634,13 +3693,103 @@ fn handle_packet(
                         bot: old.bot,
                         groups: groups,
                         id: event.id,
-                        name: old.name
+                        name: old.name,
+                        owner: old.owner
                     }
                 }))
             } else {
                 Reply::None
             }
         },
+        Packet::WebhookCreate(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            // Every message in the channel is sent wherever the webhook points,
+            // so only the owner gets to decide where that is
+            if id != config.owner_id {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            let channel = unwrap_or_err!(db.get_channel(event.channel), common::ERR_UNKNOWN_CHANNEL);
+            if !webhooks::valid_url(&event.url, &config.webhook_allowed_hosts) {
+                return Reply::Reply(Packet::Err(common::ERR_WEBHOOK_INVALID));
+            }
+
+            let allowed_hosts = config.webhook_allowed_hosts.clone();
+            // Looking the host up can take a while
+            Reply::Query(Box::new(move |db: &SqlConnection| {
+                if !webhooks::resolves_publicly(&event.url, &allowed_hosts) {
+                    return Reply::Reply(Packet::Err(common::ERR_WEBHOOK_INVALID));
+                }
+
+                let secret = attempt_or!(gen_random(webhooks::SECRET_LEN), {
+                    eprintln!("Failed to generate webhook secret");
+                    return Reply::Close;
+                });
+                db.execute(
+                    "INSERT INTO webhooks (channel, secret, url) VALUES (?, ?, ?)",
+                    &[&(channel.id as i64), &secret, &event.url]
+                ).unwrap();
+
+                Reply::Reply(Packet::WebhookReceive(common::WebhookReceive {
+                    inner: common::Webhook {
+                        channel: channel.id,
+                        id: db.last_insert_rowid() as usize,
+                        secret: secret,
+                        url: event.url
+                    }
+                }))
+            }))
+        },
+        Packet::WebhookDelete(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let channel: Option<i64> = db.query_row(
+                "SELECT channel FROM webhooks WHERE id = ?",
+                &[&(event.id as i64)],
+                |row| row.get(0)
+            ).ok();
+            let channel = unwrap_or_err!(channel, common::ERR_UNKNOWN_WEBHOOK);
+            let channel = db.get_channel(channel as usize).unwrap();
+            if !has_perm(
+                config,
+                id,
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_MANAGE_CHANNELS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            db.execute("DELETE FROM webhook_queue WHERE webhook = ?", &[&(event.id as i64)]).unwrap();
+            db.execute("DELETE FROM webhooks WHERE id = ?", &[&(event.id as i64)]).unwrap();
+            Reply::None
+        },
+        Packet::WebhookList(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let channel = unwrap_or_err!(db.get_channel(event.channel), common::ERR_UNKNOWN_CHANNEL);
+            if !has_perm(
+                config,
+                id,
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_MANAGE_CHANNELS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let session = sessions.get_mut(&conn_id).unwrap();
+            let mut stmt = db.prepare_cached("SELECT * FROM webhooks WHERE channel = ?").unwrap();
+            let mut rows = stmt.query(&[&(channel.id as i64)]).unwrap();
+
+            while let Some(row) = rows.next() {
+                write(&mut session.writer, Packet::WebhookReceive(common::WebhookReceive {
+                    inner: get_webhook_by_fields(&row.unwrap())
+                }));
+            }
+            Reply::None
+        },
         _ => Reply::None
     }
 }
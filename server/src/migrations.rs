@@ -0,0 +1,175 @@
+/// Every change ever made to the schema, in order.
+/// `PRAGMA user_version` is the number of them a database has had applied.
+/// Never edit one that's been released, add a new one instead.
+pub const MIGRATIONS: &[&str] = &[
+    // 1: Everything from before there were migrations.
+    // Databases from back then already have some of it, so it only creates what's missing.
+    "CREATE TABLE IF NOT EXISTS bots (
+        id          INTEGER NOT NULL PRIMARY KEY,
+        owner       INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS channels (
+        id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        name        TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS commands (
+        args        TEXT NOT NULL,
+        bot         INTEGER NOT NULL,
+        description TEXT NOT NULL,
+        name        TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS events (
+        overrides   TEXT,
+        packet      BLOB NOT NULL,
+        recipient   INTEGER,
+        seq         INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT
+    );
+    CREATE TABLE IF NOT EXISTS groups (
+        allow   INTEGER NOT NULL,
+        deny    INTEGER NOT NULL,
+        id      INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        name    TEXT NOT NULL,
+        pos     INTEGER NOT NULL,
+        unassignable    INTEGER NOT NULL
+    );
+    INSERT OR IGNORE INTO groups VALUES (3, 0, 1, '@humans', 0, 1);
+    INSERT OR IGNORE INTO groups VALUES (3, 0, 2, '@bots',   0, 1);
+    CREATE TABLE IF NOT EXISTS invites (
+        code        TEXT NOT NULL PRIMARY KEY,
+        creator     INTEGER NOT NULL,
+        expires     INTEGER,
+        groups      TEXT NOT NULL DEFAULT '',
+        max_uses    INTEGER,
+        uses        INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        author      INTEGER NOT NULL,
+        channel     INTEGER NOT NULL,
+        id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        text        BLOB NOT NULL,
+        timestamp   INTEGER NOT NULL,
+        timestamp_edit  INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS overrides (
+        allow       INTEGER NOT NULL,
+        channel     INTEGER NOT NULL,
+        deny        INTEGER NOT NULL,
+        [group]     INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS presence (
+        custom      TEXT,
+        status      TEXT NOT NULL,
+        user        INTEGER NOT NULL PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS totp (
+        confirmed   INTEGER NOT NULL DEFAULT 0,
+        last_step   INTEGER NOT NULL DEFAULT 0,
+        secret      BLOB NOT NULL,
+        user        INTEGER NOT NULL PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS users (
+        ban         INTEGER NOT NULL DEFAULT 0,
+        bot         INTEGER NOT NULL,
+        groups      TEXT NOT NULL DEFAULT '',
+        id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        last_ip     TEXT NOT NULL,
+        name        TEXT NOT NULL COLLATE NOCASE,
+        password    TEXT NOT NULL,
+        token       TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS recovery_codes (
+        code        TEXT NOT NULL,
+        user        INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tokens (
+        created     INTEGER NOT NULL,
+        id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        last_ip     TEXT NOT NULL,
+        last_used   INTEGER NOT NULL,
+        name        TEXT NOT NULL,
+        token       TEXT NOT NULL,
+        user        INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS webhooks (
+        channel     INTEGER NOT NULL,
+        id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        secret      TEXT NOT NULL,
+        url         TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS webhook_queue (
+        attempts        INTEGER NOT NULL,
+        body            TEXT NOT NULL,
+        id              INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        next_attempt    INTEGER NOT NULL,
+        webhook         INTEGER NOT NULL
+    );
+    -- Move tokens from back when every user only had one
+    INSERT INTO tokens (created, last_ip, last_used, name, token, user)
+    SELECT 0, last_ip, 0, 'legacy', token, id FROM users WHERE token != '';
+    UPDATE users SET token = '' WHERE token != '';",
+    // 2: Group membership gets its own table, instead of a comma separated list
+    "CREATE TABLE user_groups (
+        [group]     INTEGER NOT NULL REFERENCES groups (id) ON DELETE CASCADE,
+        user        INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        PRIMARY KEY (user, [group])
+    );
+    WITH RECURSIVE split (user, [group], rest) AS (
+        SELECT id, '', groups || ',' FROM users
+        UNION ALL
+        SELECT user, substr(rest, 1, instr(rest, ',') - 1), substr(rest, instr(rest, ',') + 1)
+        FROM split WHERE rest != ''
+    )
+    INSERT INTO user_groups (user, [group])
+    SELECT DISTINCT user, CAST([group] AS INTEGER) FROM split
+    WHERE [group] != '' AND CAST([group] AS INTEGER) IN (SELECT id FROM groups);
+    -- SQLite can't drop columns, so the old list is just left empty
+    UPDATE users SET groups = '';",
+    // 3: Per-channel retention, and an index so pruning doesn't scan every message
+    "ALTER TABLE channels ADD COLUMN retention_days INTEGER;
+    CREATE INDEX messages_channel_timestamp ON messages (channel, timestamp);",
+    // 4: Remember which accounts an auth provider created, so it can't take over anybody else's.
+    // Accounts without a password can only have come from one.
+    "ALTER TABLE users ADD COLUMN provisioned INTEGER NOT NULL DEFAULT 0;
+    UPDATE users SET provisioned = 1 WHERE password = '' AND bot = 0;",
+    // 5: Incoming webhooks get their own tokens, which can only post to one channel
+    "CREATE TABLE incoming_webhooks (
+        bot         INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        channel     INTEGER NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+        id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+        token       TEXT NOT NULL UNIQUE
+    );",
+    // 6: The journal is also trimmed by age. Older events are from before that, so they go first.
+    "ALTER TABLE events ADD COLUMN timestamp INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX events_timestamp ON events (timestamp);",
+    // 7: Remember which message journaled and queued packets are about, so pruning it can delete them too
+    "ALTER TABLE events ADD COLUMN message INTEGER;
+    CREATE INDEX events_message ON events (message);
+    ALTER TABLE webhook_queue ADD COLUMN message INTEGER;
+    CREATE INDEX webhook_queue_message ON webhook_queue (message);"
+];
+
+#[cfg(test)]
+#[test]
+fn test() {
+    use migrate;
+    use rusqlite::Connection as SqlConnection;
+
+    let mut db = SqlConnection::open_in_memory().unwrap();
+    db.execute_batch(MIGRATIONS[0]).unwrap();
+    db.execute_batch("PRAGMA user_version = 1").unwrap();
+    db.execute_batch("INSERT INTO groups VALUES (0, 0, 3, 'mods', 1, 0);
+                      INSERT INTO users (bot, groups, last_ip, name, password, token)
+                      VALUES (0, '3,42', '', 'jd', '', '')").unwrap();
+
+    assert_eq!(migrate::migrate(&mut db, MIGRATIONS).unwrap(), MIGRATIONS.len() - 1);
+    assert_eq!(migrate::version(&db).unwrap() as usize, MIGRATIONS.len());
+    assert_eq!(migrate::migrate(&mut db, MIGRATIONS).unwrap(), 0);
+
+    // Groups that were deleted before there were foreign keys are dropped
+    let groups: Vec<i64> = {
+        let mut stmt = db.prepare("SELECT [group] FROM user_groups WHERE user = 1").unwrap();
+        let rows = stmt.query_map(&[], |row| row.get(0)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
+    assert_eq!(groups, vec![3]);
+}
@@ -0,0 +1,163 @@
+use common;
+use rusqlite::Connection as SqlConnection;
+use std::collections::HashMap;
+
+/// Reading and writing channels, groups, messages, users and overrides.
+pub trait Storage {
+    fn get_channel(&self, id: usize) -> Option<common::Channel>;
+    fn get_channel_overrides(&self, channel: usize) -> HashMap<usize, (u8, u8)>;
+    fn get_channels(&self) -> Vec<common::Channel>;
+    fn get_group(&self, id: usize) -> Option<common::Group>;
+    fn get_groups(&self) -> Vec<common::Group>;
+    fn get_message(&self, id: usize) -> Option<common::Message>;
+    fn get_user(&self, id: usize) -> Option<common::User>;
+    fn get_user_groups(&self, user: usize) -> Vec<usize>;
+    /// The `(allow, deny)` of every group `user` is in, including `@humans` or `@bots`, lowest first
+    fn get_user_permissions(&self, bot: bool, user: usize) -> Vec<(u8, u8)>;
+    /// Replaces the overrides of `channel`. Overrides for groups that don't exist are skipped.
+    fn insert_channel_overrides(&self, channel: usize, overrides: &HashMap<usize, (u8, u8)>);
+    /// Replaces the groups `user` is in. Groups that don't exist (anymore) are skipped.
+    fn set_user_groups(&self, user: usize, groups: &[usize]);
+}
+
+impl Storage for SqlConnection {
+    fn get_channel(&self, id: usize) -> Option<common::Channel> {
+        let mut stmt = self.prepare_cached("SELECT * FROM channels WHERE id = ?").unwrap();
+        let mut rows = stmt.query(&[&(id as i64)]).unwrap();
+        if let Some(row) = rows.next() {
+            let row = row.unwrap();
+            Some(super::get_channel_by_fields(self, &row))
+        } else {
+            None
+        }
+    }
+    fn get_channel_overrides(&self, channel: usize) -> HashMap<usize, (u8, u8)> {
+        let mut stmt = self.prepare_cached("SELECT [group], allow, deny FROM overrides WHERE channel = ?").unwrap();
+        let mut rows = stmt.query(&[&(channel as i64)]).unwrap();
+
+        let mut overrides = HashMap::new();
+
+        while let Some(row) = rows.next() {
+            let row = row.unwrap();
+            overrides.insert(row.get::<_, i64>(0) as usize, (row.get(1), row.get(2)));
+        }
+
+        overrides
+    }
+    fn get_channels(&self) -> Vec<common::Channel> {
+        let mut stmt = self.prepare_cached("SELECT * FROM channels").unwrap();
+        let mut rows = stmt.query(&[]).unwrap();
+
+        let mut channels = Vec::new();
+        while let Some(row) = rows.next() {
+            channels.push(super::get_channel_by_fields(self, &row.unwrap()));
+        }
+        channels
+    }
+    fn get_group(&self, id: usize) -> Option<common::Group> {
+        let mut stmt = self.prepare_cached("SELECT * FROM groups WHERE id = ?").unwrap();
+        let mut rows = stmt.query(&[&(id as i64)]).unwrap();
+        if let Some(row) = rows.next() {
+            let row = row.unwrap();
+            Some(super::get_group_by_fields(&row))
+        } else {
+            None
+        }
+    }
+    fn get_groups(&self) -> Vec<common::Group> {
+        let mut stmt = self.prepare_cached("SELECT * FROM groups").unwrap();
+        let rows = stmt.query_map(&[], |row| super::get_group_by_fields(row)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+    fn get_message(&self, id: usize) -> Option<common::Message> {
+        let mut stmt = self.prepare_cached("SELECT * FROM messages WHERE id = ?")
+            .unwrap();
+        let mut rows = stmt.query(&[&(id as i64)]).unwrap();
+        if let Some(row) = rows.next() {
+            let row = row.unwrap();
+            Some(super::get_message_by_fields(&row))
+        } else {
+            None
+        }
+    }
+    fn get_user(&self, id: usize) -> Option<common::User> {
+        let mut stmt = self.prepare_cached("SELECT * FROM users WHERE id = ?").unwrap();
+        let mut rows = stmt.query(&[&(id as i64)]).unwrap();
+
+        if let Some(row) = rows.next() {
+            Some(super::get_user_by_fields(self, &row.unwrap()))
+        } else {
+            None
+        }
+    }
+    fn get_user_groups(&self, user: usize) -> Vec<usize> {
+        let mut stmt = self.prepare_cached("SELECT [group] FROM user_groups WHERE user = ? ORDER BY [group]").unwrap();
+        let rows = stmt.query_map(&[&(user as i64)], |row| row.get::<_, i64>(0) as usize).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+    fn get_user_permissions(&self, bot: bool, user: usize) -> Vec<(u8, u8)> {
+        let mut stmt = self.prepare_cached(
+            "SELECT allow, deny FROM groups
+            WHERE id = ? OR id IN (SELECT [group] FROM user_groups WHERE user = ?)
+            ORDER BY pos"
+        ).unwrap();
+        let everyone: i64 = if bot { 2 } else { 1 };
+        let rows = stmt.query_map(&[&everyone, &(user as i64)], |row| (row.get(0), row.get(1))).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+    fn insert_channel_overrides(&self, channel: usize, overrides: &HashMap<usize, (u8, u8)>) {
+        // A savepoint and not a transaction, since the caller might already be in one
+        self.execute_batch("SAVEPOINT insert_channel_overrides").unwrap();
+        self.execute("DELETE FROM overrides WHERE channel = ?", &[&(channel as i64)]).unwrap();
+
+        let mut stmt_exists = self.prepare_cached("SELECT COUNT(*) FROM groups WHERE id = ?").unwrap();
+        let mut stmt_insert = self.prepare_cached("INSERT INTO overrides (allow, channel, deny, [group]) VALUES (?, ?, ?, ?)")
+            .unwrap();
+
+        for (id, &(allow, deny)) in overrides {
+            let count: i64 = stmt_exists.query_row(
+                &[&(*id as i64)],
+                |row| row.get(0)
+            ).unwrap();
+            if count != 0 {
+                stmt_insert.execute(&[&allow, &(channel as i64), &deny, &(*id as i64)]).unwrap();
+            }
+        }
+        self.execute_batch("RELEASE insert_channel_overrides").unwrap();
+    }
+    fn set_user_groups(&self, user: usize, groups: &[usize]) {
+        self.execute_batch("SAVEPOINT set_user_groups").unwrap();
+        self.execute("DELETE FROM user_groups WHERE user = ?", &[&(user as i64)]).unwrap();
+        for &group in groups {
+            self.execute(
+                "INSERT OR IGNORE INTO user_groups (user, [group]) SELECT ?, id FROM groups WHERE id = ?",
+                &[&(user as i64), &(group as i64)]
+            ).unwrap();
+        }
+        self.execute_batch("RELEASE set_user_groups").unwrap();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    use migrate;
+    use migrations::MIGRATIONS;
+
+    let mut db = SqlConnection::open_in_memory().unwrap();
+    migrate::migrate(&mut db, MIGRATIONS).unwrap();
+    db.execute_batch("INSERT INTO channels (name) VALUES ('general');
+                      INSERT INTO groups VALUES (1, 0, 3, 'mods', 1, 0);
+                      INSERT INTO users (bot, last_ip, name, password, token) VALUES (0, '', 'jd', '', '')").unwrap();
+
+    let mut overrides = HashMap::new();
+    overrides.insert(3, (1, 2));
+    overrides.insert(42, (1, 2));
+    db.insert_channel_overrides(1, &overrides);
+    overrides.remove(&42);
+    assert_eq!(db.get_channel(1).unwrap().overrides, overrides);
+
+    db.set_user_groups(1, &[3, 42]);
+    assert_eq!(db.get_user(1).unwrap().groups, vec![3]);
+    assert_eq!(db.get_user_permissions(false, 1), vec![(3, 0), (1, 0)]);
+}
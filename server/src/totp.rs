@@ -0,0 +1,84 @@
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+pub const DIGITS: usize = 6;
+pub const SECRET_LEN: usize = 20;
+pub const STEP: i64 = 30;
+pub const WINDOW: i64 = 1;
+
+const BASE32_CHARS: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn base32(input: &[u8]) -> String {
+    let mut output = String::with_capacity((input.len() * 8 + 4) / 5);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for &byte in input {
+        buffer = ((buffer << 8) | byte as u32) & 0xFFFF;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            output.push(BASE32_CHARS[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        output.push(BASE32_CHARS[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    output
+}
+pub fn code(secret: &[u8], step: i64) -> Result<u32, ErrorStack> {
+    let mut counter = [0; 8];
+    for (i, byte) in counter.iter_mut().enumerate() {
+        *byte = (step as u64 >> (56 - i * 8)) as u8;
+    }
+
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(&counter)?;
+    let hash = signer.sign_to_vec()?;
+
+    let offset = (hash[hash.len() - 1] & 0xF) as usize;
+    let binary = ((hash[offset] as u32 & 0x7F) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | hash[offset + 3] as u32;
+
+    Ok(binary % 10u32.pow(DIGITS as u32))
+}
+/// Returns the time step `input` is valid for, if any.
+/// Allows for `WINDOW` steps of clock drift in each direction.
+pub fn verify(secret: &[u8], input: &str, now: i64) -> Result<Option<i64>, ErrorStack> {
+    let input = input.trim();
+    if input.len() != DIGITS {
+        return Ok(None);
+    }
+    let input: u32 = match input.parse() {
+        Ok(ok) => ok,
+        Err(_) => return Ok(None)
+    };
+
+    let current = now / STEP;
+    for step in current - WINDOW..current + WINDOW + 1 {
+        if code(secret, step)? == input {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    // Test vectors from RFC 4648 and RFC 6238
+    assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+    assert_eq!(base32(b"fooba"), "MZXW6YTB");
+
+    let secret = b"12345678901234567890";
+    assert_eq!(code(secret, 59 / STEP).unwrap(), 287082);
+    assert_eq!(code(secret, 1111111109 / STEP).unwrap(), 81804);
+    assert_eq!(verify(secret, "081804", 1111111109 + STEP).unwrap(), Some(1111111109 / STEP));
+    assert_eq!(verify(secret, "081804", 1111111109 + STEP * 2).unwrap(), None);
+}
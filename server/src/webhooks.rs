@@ -0,0 +1,424 @@
+use chrono::Utc;
+use common::{self, Packet};
+use db::Pool;
+use futures::{future, Future};
+use hyper::client::HttpConnector;
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Uri};
+use hyper_tls::HttpsConnector;
+use native_tls::TlsConnector;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rusqlite::Connection as SqlConnection;
+use serde_json::{self, Value};
+use std::cmp;
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::vec;
+use tokio;
+use tokio::timer::Timeout;
+
+pub const ATTEMPTS: i64 = 10;
+pub const BACKOFF_SECONDS: i64 = 5;
+pub const BACKOFF_MAX_SECONDS: i64 = 60*60;
+pub const BATCH: i64 = 64;
+pub const SECRET_LEN: usize = 32;
+pub const TIMEOUT_SECONDS: u64 = 10;
+
+struct Delivery {
+    attempts: i64,
+    body: String,
+    id: i64,
+    secret: String,
+    url: String
+}
+
+/// Resolves names like usual, but leaves out every address that isn't public.
+/// The name could point somewhere else by the time a delivery is made, so checking it once isn't enough.
+#[derive(Clone)]
+struct PublicResolver {
+    allowed_hosts: Arc<Vec<String>>,
+    inner: GaiResolver
+}
+impl Resolve for PublicResolver {
+    type Addrs = vec::IntoIter<IpAddr>;
+    type Future = Box<Future<Item = Self::Addrs, Error = io::Error> + Send>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        let allowed = host_allowed(name.as_str(), &self.allowed_hosts);
+        Box::new(self.inner.resolve(name).and_then(move |addrs| {
+            let addrs: Vec<IpAddr> = addrs.filter(|&ip| allowed || is_public(ip)).collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::Other, "the host doesn't have a public address"));
+            }
+            Ok(addrs.into_iter())
+        }))
+    }
+}
+
+/// Delivers queued events. Deliveries are stored in the database,
+/// so nothing is lost if the server restarts or the target is down for a while.
+pub struct Deliverer {
+    allowed_hosts: Arc<Vec<String>>,
+    client: Client<HttpsConnector<HttpConnector<PublicResolver>>>,
+    in_flight: Arc<Mutex<HashSet<i64>>>,
+    pool: Arc<Pool>
+}
+impl Deliverer {
+    /// Webhooks are only ever sent to public addresses, or to `allowed_hosts`.
+    pub fn new(pool: Arc<Pool>, allowed_hosts: Vec<String>) -> Option<Deliverer> {
+        let allowed_hosts = Arc::new(allowed_hosts);
+        let mut http = HttpConnector::new_with_resolver(PublicResolver {
+            allowed_hosts: Arc::clone(&allowed_hosts),
+            inner: GaiResolver::new(4)
+        });
+        // Otherwise it refuses https
+        http.enforce_http(false);
+        let tls = TlsConnector::new().ok()?;
+
+        Some(Deliverer {
+            allowed_hosts: allowed_hosts,
+            client: Client::builder().build(HttpsConnector::from((http, tls))),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            pool: pool
+        })
+    }
+    /// Sends everything that's due and not already on its way.
+    pub fn deliver(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        let allowed_hosts = Arc::clone(&self.allowed_hosts);
+        let client = self.client.clone();
+        let in_flight = Arc::clone(&self.in_flight);
+        let pool = Arc::clone(&self.pool);
+
+        let due = self.pool.run(|db| {
+            let mut stmt = db.prepare_cached(
+                "SELECT webhook_queue.id, webhook_queue.attempts, webhook_queue.body, webhooks.secret, webhooks.url
+                FROM webhook_queue JOIN webhooks ON webhook_queue.webhook = webhooks.id
+                WHERE webhook_queue.next_attempt <= ?
+                ORDER BY webhook_queue.id
+                LIMIT ?"
+            ).unwrap();
+            let mut rows = stmt.query(&[&Utc::now().timestamp(), &BATCH]).unwrap();
+
+            let mut due = Vec::new();
+            while let Some(row) = rows.next() {
+                let row = row.unwrap();
+                due.push(Delivery {
+                    attempts: row.get(1),
+                    body: row.get(2),
+                    id: row.get(0),
+                    secret: row.get(3),
+                    url: row.get(4)
+                });
+            }
+            due
+        });
+
+        Box::new(due.map_err(|_| ()).map(move |due| {
+            for delivery in due {
+                let Delivery { attempts, body, id, secret, url } = delivery;
+                if !in_flight.lock().unwrap().insert(id) {
+                    continue;
+                }
+                // Webhooks from before addresses were checked
+                if !valid_url(&url, &allowed_hosts) {
+                    eprintln!("Dropping webhook delivery #{} to {}, which isn't a public address", id, url);
+                    let in_flight = Arc::clone(&in_flight);
+                    pool.execute(move |db| {
+                        db.execute("DELETE FROM webhook_queue WHERE id = ?", &[&id]).unwrap();
+                        in_flight.lock().unwrap().remove(&id);
+                    });
+                    continue;
+                }
+
+                let request = match (sign(&secret, &body), url.parse::<Uri>()) {
+                    (Ok(signature), Ok(uri)) => Request::post(uri)
+                        .header(CONTENT_TYPE, "application/json")
+                        .header("X-Synac-Delivery", &*id.to_string())
+                        .header("X-Synac-Signature", &*signature)
+                        .body(Body::from(body))
+                        .ok(),
+                    _ => None
+                };
+                let request = match request {
+                    Some(request) => request,
+                    None => {
+                        eprintln!("Failed to prepare webhook delivery #{}", id);
+                        in_flight.lock().unwrap().remove(&id);
+                        continue;
+                    }
+                };
+
+                let in_flight = Arc::clone(&in_flight);
+                let pool = Arc::clone(&pool);
+                let response = Timeout::new(client.request(request), Duration::from_secs(TIMEOUT_SECONDS));
+
+                tokio::spawn(response.then(move |result| {
+                    let success = result.map(|response| response.status().is_success()).unwrap_or(false);
+
+                    pool.execute(move |db| {
+                        if success {
+                            db.execute("DELETE FROM webhook_queue WHERE id = ?", &[&id]).unwrap();
+                        } else if attempts + 1 >= ATTEMPTS {
+                            eprintln!("Giving up on webhook delivery #{} to {}", id, url);
+                            db.execute("DELETE FROM webhook_queue WHERE id = ?", &[&id]).unwrap();
+                        } else {
+                            let backoff = cmp::min(BACKOFF_SECONDS << attempts, BACKOFF_MAX_SECONDS);
+                            db.execute(
+                                "UPDATE webhook_queue SET attempts = attempts + 1, next_attempt = ? WHERE id = ?",
+                                &[&(Utc::now().timestamp() + backoff), &id]
+                            ).unwrap();
+                        }
+                        in_flight.lock().unwrap().remove(&id);
+                    });
+                    future::ok::<(), ()>(())
+                }));
+            }
+        }))
+    }
+}
+
+/// Queues `packet` for every webhook registered on `channel`.
+pub fn queue(db: &SqlConnection, channel: usize, packet: &Packet) {
+    let mut stmt = db.prepare_cached("SELECT id FROM webhooks WHERE channel = ?").unwrap();
+    let mut rows = stmt.query(&[&(channel as i64)]).unwrap();
+
+    let mut body = None;
+    while let Some(row) = rows.next() {
+        let webhook: i64 = row.unwrap().get(0);
+        if body.is_none() {
+            body = Some(to_json(packet));
+        }
+
+        db.execute(
+            "INSERT INTO webhook_queue (attempts, body, message, next_attempt, webhook) VALUES (0, ?, ?, ?, ?)",
+            &[
+                body.as_ref().unwrap(),
+                &super::message_of(packet).map(|id| id as i64),
+                &Utc::now().timestamp(),
+                &webhook
+            ]
+        ).unwrap();
+    }
+}
+/// Serializes `packet` for a webhook.
+/// Message text is bytes on the wire, which JSON would turn into a list of numbers.
+fn to_json(packet: &Packet) -> String {
+    let mut json = serde_json::to_value(packet).unwrap();
+    if let Packet::MessageReceive(ref event) = *packet {
+        json["message_receive"]["inner"]["text"] =
+            Value::String(String::from_utf8_lossy(&event.inner.text).into_owned());
+    }
+    json.to_string()
+}
+/// Returns the `X-Synac-Signature` header for `body`.
+pub fn sign(secret: &str, body: &str) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(body.as_bytes())?;
+
+    let mut signature = String::from("sha256=");
+    for byte in &signer.sign_to_vec()? {
+        signature.push_str(&format!("{:02x}", byte));
+    }
+    Ok(signature)
+}
+pub fn valid_url(url: &str, allowed_hosts: &[String]) -> bool {
+    if url.len() > common::LIMIT_MESSAGE {
+        return false;
+    }
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return false
+    };
+    if uri.scheme_str() != Some("http") && uri.scheme_str() != Some("https") {
+        return false;
+    }
+    let host = match uri.host() {
+        Some(host) => host,
+        None => return false
+    };
+    if host_allowed(host, allowed_hosts) {
+        return true;
+    }
+    // Names are checked whenever they're resolved
+    match host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+        Ok(ip) => is_public(ip),
+        Err(_) => true
+    }
+}
+/// Like `valid_url`, but also makes sure the host resolves to public addresses only.
+/// This blocks, so don't call it on the state thread.
+pub fn resolves_publicly(url: &str, allowed_hosts: &[String]) -> bool {
+    if !valid_url(url, allowed_hosts) {
+        return false;
+    }
+    let uri: Uri = url.parse().unwrap();
+    let host = uri.host().unwrap();
+    if host_allowed(host, allowed_hosts) {
+        return true;
+    }
+    // The port doesn't change where the name points
+    match (host.trim_matches(|c| c == '[' || c == ']'), 80).to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<_> = addrs.collect();
+            !addrs.is_empty() && addrs.iter().all(|addr| is_public(addr.ip()))
+        },
+        Err(_) => false
+    }
+}
+fn host_allowed(host: &str, allowed_hosts: &[String]) -> bool {
+    allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host))
+}
+/// Whether `ip` is reachable from anywhere, and not just from the server's own network.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast()
+                || octets[0] == 0
+                // Shared address space, RFC 6598
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+        },
+        IpAddr::V6(ip) => {
+            // Mapped and compatible IPv4 addresses, including :: and ::1
+            if let Some(ip) = ip.to_ipv4() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_multicast()
+                // Unique local, RFC 4193
+                || first & 0xfe00 == 0xfc00
+                // Link-local
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    // Test vector from RFC 4231
+    assert_eq!(
+        sign("Jefe", "what do ya want for nothing?").unwrap(),
+        "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    assert!(valid_url("https://example.com/hook", &[]));
+    assert!(!valid_url("ftp://example.com/hook", &[]));
+    assert!(!valid_url("/hook", &[]));
+    for url in &[
+        "http://127.0.0.1:8080/hook",
+        "http://10.0.0.1/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]/hook",
+        "http://[::ffff:192.168.0.1]/hook",
+        "http://[fd00::1]/hook"
+    ] {
+        assert!(!valid_url(url, &[]), "{} is private", url);
+    }
+    assert!(valid_url("http://127.0.0.1:8080/hook", &[String::from("127.0.0.1")]));
+    assert!(!resolves_publicly("http://localhost:8080/hook", &[]));
+    assert!(resolves_publicly("http://LOCALHOST:8080/hook", &[String::from("localhost")]));
+    assert!(is_public("1.1.1.1".parse().unwrap()));
+    assert!(is_public("2606:4700::1111".parse().unwrap()));
+}
+#[cfg(test)]
+#[test]
+fn test_deliver() {
+    use migrate;
+    use migrations::MIGRATIONS;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use tokio::runtime::Runtime;
+
+    let path = env::temp_dir().join(format!("synac-webhooks-{}.sqlite", ::std::process::id()));
+    let mut db = ::db::open(&path).unwrap();
+    migrate::migrate(&mut db, MIGRATIONS).unwrap();
+
+    // Fails the first delivery, and accepts the second
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for status in &["500 Internal Server Error", "200 OK"] {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            let (head, body) = loop {
+                let read = conn.read(&mut buf).unwrap();
+                assert!(read > 0, "connection closed before the request was complete");
+                request.extend_from_slice(&buf[..read]);
+
+                let text = String::from_utf8_lossy(&request).into_owned();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let head = text[..end].to_lowercase();
+                    let len: usize = head.lines()
+                        .find(|line| line.starts_with("content-length:"))
+                        .map(|line| line["content-length:".len()..].trim().parse().unwrap())
+                        .unwrap_or(0);
+                    if text.len() >= end + 4 + len {
+                        break (head, text[end + 4..end + 4 + len].to_string());
+                    }
+                }
+            };
+            write!(conn, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            sender.send((head, body)).unwrap();
+        }
+    });
+
+    db.execute("INSERT INTO webhooks (channel, secret, url) VALUES (1, 'secret', ?)", &[&url]).unwrap();
+    queue(&db, 1, &Packet::MessageReceive(common::MessageReceive {
+        inner: common::Message {
+            author: 1,
+            channel: 1,
+            id: 1,
+            text: b"hi".to_vec(),
+            timestamp: 0,
+            timestamp_edit: None
+        },
+        new: true
+    }));
+
+    let pool = Arc::new(Pool::new(&path, 1).unwrap());
+    let deliverer = Deliverer::new(Arc::clone(&pool), vec![String::from("127.0.0.1")]).unwrap();
+    let mut runtime = Runtime::new().unwrap();
+    fn wait_for(db: &SqlConnection, query: &str) {
+        for _ in 0..100 {
+            let count: i64 = db.query_row(query, &[], |row| row.get(0)).unwrap();
+            if count != 0 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("timed out waiting for {}", query);
+    }
+
+    runtime.spawn(deliverer.deliver());
+    let (head, body) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    let json: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["message_receive"]["inner"]["text"], "hi");
+    assert!(head.contains(&format!("x-synac-signature: {}", sign("secret", &body).unwrap())));
+    wait_for(&db, "SELECT COUNT(*) FROM webhook_queue WHERE attempts = 1");
+
+    // Don't wait for the backoff
+    db.execute("UPDATE webhook_queue SET next_attempt = 0", &[]).unwrap();
+    runtime.spawn(deliverer.deliver());
+    let (_, retry) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(retry, body);
+    wait_for(&db, "SELECT COUNT(*) = 0 FROM webhook_queue");
+
+    pool.close();
+    db.close().unwrap();
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,59 @@
+use common;
+use openssl::rand;
+use openssl::rsa::{Rsa, PKCS1_OAEP_PADDING};
+use openssl::symm::{self, Cipher};
+use Error;
+
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+
+/// Encrypts `input` for whoever has the private half of `rsa`.
+/// RSA can only take a few bytes, so it encrypts a random AES key that encrypts the rest.
+/// The output is the size of the encrypted key as two bytes, the encrypted key, and the encrypted input.
+pub fn encrypt(input: &[u8], rsa: &Rsa) -> Result<Vec<u8>, Error> {
+    let mut secret = [0; KEY_LEN + IV_LEN];
+    rand::rand_bytes(&mut secret)?;
+    let (key, iv) = secret.split_at(KEY_LEN);
+
+    let mut encrypted_secret = vec![0; rsa.size() as usize];
+    let len = rsa.public_encrypt(&secret, &mut encrypted_secret, PKCS1_OAEP_PADDING)?;
+    encrypted_secret.truncate(len);
+
+    let mut output = common::encode_u16(len as u16).to_vec();
+    output.extend_from_slice(&encrypted_secret);
+    output.extend(symm::encrypt(Cipher::aes_256_cbc(), key, Some(iv), input)?);
+    Ok(output)
+}
+/// Decrypts what `encrypt` made, with the private key it was made for.
+pub fn decrypt(input: &[u8], rsa: &Rsa) -> Result<Vec<u8>, Error> {
+    if input.len() < 2 {
+        return Err(Error::InvalidMessage);
+    }
+    let len = common::decode_u16(&input[..2]) as usize;
+    if input.len() < 2 + len {
+        return Err(Error::InvalidMessage);
+    }
+    let (encrypted_secret, encrypted) = input[2..].split_at(len);
+
+    let mut secret = vec![0; rsa.size() as usize];
+    let len = rsa.private_decrypt(encrypted_secret, &mut secret, PKCS1_OAEP_PADDING)?;
+    if len != KEY_LEN + IV_LEN {
+        return Err(Error::InvalidMessage);
+    }
+    let (key, iv) = secret[..len].split_at(KEY_LEN);
+
+    Ok(symm::decrypt(Cipher::aes_256_cbc(), key, Some(iv), encrypted)?)
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let rsa = Rsa::generate(common::RSA_LENGTH).unwrap();
+    let encrypted = encrypt(b"hi", &rsa).unwrap();
+    assert_eq!(decrypt(&encrypted, &rsa).unwrap(), b"hi".to_vec());
+
+    match decrypt(&encrypted[..1], &rsa) {
+        Err(Error::InvalidMessage) => (),
+        _ => panic!("a truncated message was decrypted")
+    }
+}
@@ -0,0 +1,108 @@
+#[macro_use] extern crate failure;
+pub extern crate common;
+extern crate openssl;
+
+mod crypto;
+mod listener;
+mod state;
+
+pub use crypto::{decrypt, encrypt};
+pub use listener::Listener;
+pub use state::State;
+
+use common::Packet;
+use openssl::ssl::{SslConnectorBuilder, SslMethod, SslStream, SSL_VERIFY_NONE};
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "{}", _0)]
+    CommonError(#[cause] common::Error),
+    #[fail(display = "TLS handshake failed: {}", _0)]
+    HandshakeError(String),
+    #[fail(display = "{}", _0)]
+    IoError(#[cause] io::Error),
+    #[fail(display = "the message isn't something encrypt() made")]
+    InvalidMessage,
+    #[fail(display = "the server's public key doesn't match the one you were given")]
+    PublicKeyMismatch,
+    #[fail(display = "{}", _0)]
+    SslError(#[cause] openssl::error::ErrorStack)
+}
+impl From<common::Error> for Error {
+    fn from(err: common::Error) -> Self {
+        // Callers look for IoError to know when to reconnect
+        match err {
+            common::Error::IoError(err) => Error::IoError(err),
+            err => Error::CommonError(err)
+        }
+    }
+}
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        Error::SslError(err)
+    }
+}
+
+/// The SHA-256 of a public key in PEM, as the uppercase hex the server prints on startup.
+pub fn hash_public_key(pem: &[u8]) -> String {
+    let hash = openssl::sha::sha256(pem);
+    let mut hex = String::with_capacity(64);
+    for byte in &hash {
+        hex.push_str(&format!("{:02X}", byte));
+    }
+    hex
+}
+
+/// A connection to a server.
+pub struct Session {
+    stream: SslStream<TcpStream>
+}
+impl Session {
+    /// Connects to `addr`, and makes sure its public key hashes to `hash`.
+    /// Servers usually have self-signed certificates, so that's all that's checked.
+    pub fn new(addr: SocketAddr, hash: String) -> Result<Session, Error> {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls())?;
+        builder.builder_mut().set_verify(SSL_VERIFY_NONE);
+        let connector = builder.build();
+
+        let stream = TcpStream::connect(addr)?;
+        let stream = connector
+            .danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+            .map_err(|err| Error::HandshakeError(err.to_string()))?;
+
+        let matches = match stream.ssl().peer_certificate() {
+            Some(cert) => hash_public_key(&cert.public_key()?.public_key_to_pem()?).eq_ignore_ascii_case(hash.trim()),
+            None => false
+        };
+        if !matches {
+            return Err(Error::PublicKeyMismatch);
+        }
+
+        Ok(Session {
+            stream: stream
+        })
+    }
+    /// The TLS stream, for example to make it non-blocking or to read from it with a `Listener`.
+    pub fn inner_stream(&mut self) -> &mut SslStream<TcpStream> {
+        &mut self.stream
+    }
+    /// Reads one packet, waiting for it if the stream is blocking.
+    pub fn read(&mut self) -> Result<Packet, Error> {
+        Ok(common::read(&mut self.stream)?)
+    }
+    /// Makes reads return `WouldBlock` instead of waiting, for use with a `Listener`.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+        Ok(self.stream.get_ref().set_nonblocking(nonblocking)?)
+    }
+    /// Sends `packet` to the server.
+    pub fn send(&mut self, packet: &Packet) -> Result<(), Error> {
+        Ok(common::write(&mut self.stream, packet)?)
+    }
+}
@@ -0,0 +1,86 @@
+use common::{self, Packet};
+use std::io::{self, Read};
+use Error;
+
+/// Reads packets from a non-blocking stream, bit by bit as they arrive.
+pub struct Listener {
+    buf: Vec<u8>,
+    read: usize,
+    /// Whether `buf` is the size of a packet or the packet itself
+    size_known: bool
+}
+impl Default for Listener {
+    fn default() -> Self {
+        Listener::new()
+    }
+}
+impl Listener {
+    pub fn new() -> Self {
+        Listener {
+            buf: vec![0; 2],
+            read: 0,
+            size_known: false
+        }
+    }
+    /// Returns the next packet if all of it has arrived, and `None` if it's still on its way.
+    pub fn try_read<T: Read>(&mut self, stream: &mut T) -> Result<Option<Packet>, Error> {
+        loop {
+            if self.read == self.buf.len() {
+                if self.size_known {
+                    let packet = common::deserialize(&self.buf).map_err(common::Error::from)?;
+                    *self = Listener::new();
+                    return Ok(Some(packet));
+                }
+                let size = common::decode_u16(&self.buf) as usize;
+                self.buf = vec![0; size];
+                self.read = 0;
+                self.size_known = true;
+                continue;
+            }
+            match stream.read(&mut self.buf[self.read..]) {
+                Ok(0) => return Err(Error::IoError(io::Error::from(io::ErrorKind::UnexpectedEof))),
+                Ok(read) => self.read += read,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(Error::IoError(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    /// Hands out one byte at a time, with nothing in between, like a slow connection
+    struct Slow {
+        data: Vec<u8>,
+        pos: usize,
+        ready: bool
+    }
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.ready = !self.ready;
+            if !self.ready || self.pos == self.data.len() {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            buf[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    let mut data = Vec::new();
+    common::write(&mut data, &Packet::Close).unwrap();
+    common::write(&mut data, &Packet::Close).unwrap();
+    let len = data.len();
+    let mut stream = Slow { data: data, pos: 0, ready: false };
+
+    let mut listener = Listener::new();
+    let mut packets = 0;
+    for _ in 0..len * 2 + 2 {
+        if let Some(Packet::Close) = listener.try_read(&mut stream).unwrap() {
+            packets += 1;
+        }
+    }
+    assert_eq!(packets, 2);
+}
@@ -0,0 +1,39 @@
+use common::{self, Packet};
+use std::collections::HashMap;
+
+/// What the server has told about its channels, groups and users so far.
+#[derive(Default)]
+pub struct State {
+    pub channels: HashMap<usize, common::Channel>,
+    pub groups: HashMap<usize, common::Group>,
+    pub users: HashMap<usize, common::User>
+}
+impl State {
+    pub fn new() -> Self {
+        State::default()
+    }
+    /// Keeps track of whatever changed in `packet`. Everything else is ignored.
+    pub fn update(&mut self, packet: &Packet) {
+        match *packet {
+            Packet::ChannelDeleteReceive(ref event) => {
+                self.channels.remove(&event.inner.id);
+            },
+            Packet::ChannelReceive(ref event) => {
+                self.channels.insert(event.inner.id, event.inner.clone());
+            },
+            Packet::GroupDeleteReceive(ref event) => {
+                self.groups.remove(&event.inner.id);
+            },
+            Packet::GroupReceive(ref event) => {
+                self.groups.insert(event.inner.id, event.inner.clone());
+            },
+            Packet::UserDeleteReceive(ref event) => {
+                self.users.remove(&event.inner.id);
+            },
+            Packet::UserReceive(ref event) => {
+                self.users.insert(event.inner.id, event.inner.clone());
+            },
+            _ => ()
+        }
+    }
+}